@@ -11,8 +11,8 @@ pub fn read_blueprint(blueprint: &str) -> Blueprint {
     let mut decomp = ZlibDecoder::new(&*data);
     let mut json = String::new();
     decomp.read_to_string(&mut json).unwrap();
-    println!("=> {}",json);
-    
+    //println!("=> {}",json);
+
     let wrapper: BlueprintWrapper = serde_json::from_str(&json).expect("bad json");
     wrapper.blueprint
 }
@@ -44,12 +44,14 @@ pub struct Entity {
     pub entity_number: u32,
     pub name: String,
     pub position: Position,
+    #[serde(default)]
     pub direction: u32, // usually 4 for us
+    #[serde(default)]
     pub control_behavior: ControlBehavior,
     pub connections: Option<HashMap<u32,Connections>> // key = circuit id
 }
 
-#[derive(Debug,Serialize,Deserialize)]
+#[derive(Debug,Serialize,Deserialize,Default)]
 pub struct ControlBehavior {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub arithmetic_conditions: Option<ArithmeticConditions>,
@@ -76,7 +78,8 @@ pub struct ArithmeticConditions {
 
 #[derive(Debug,Serialize,Deserialize)]
 pub struct DeciderConditions {
-    pub first_signal: Signal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_signal: Option<Signal>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub second_signal: Option<Signal>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -84,10 +87,11 @@ pub struct DeciderConditions {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output_signal: Option<Signal>,
     pub comparator: String,
+    #[serde(default)]
     pub copy_count_from_input: bool
 }
 
-#[derive(Debug,Serialize,Deserialize,Clone)]
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq,Eq,Hash,PartialOrd,Ord)]
 pub struct Signal {
     #[serde(rename = "type")]
     pub cat: String,
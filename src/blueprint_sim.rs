@@ -0,0 +1,310 @@
+// Runs a blueprint tick by tick, the way its combinators would run in game.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::blueprint::{ArithmeticConditions, Blueprint, DeciderConditions, Entity, Signal};
+use crate::common::BinOp;
+use crate::disjoint_set::DisjointSet;
+use crate::ir::WireColor;
+
+/// The signals on a wire or at a combinator's output. Signals with a value of zero are left out.
+pub type SignalMap = BTreeMap<Signal,i32>;
+
+fn add_signal(map: &mut SignalMap, signal: &Signal, value: i32) {
+    let sum = map.get(signal).copied().unwrap_or(0).wrapping_add(value);
+    if sum == 0 {
+        map.remove(signal);
+    } else {
+        map.insert(signal.clone(),sum);
+    }
+}
+
+/// The virtual signals with special meanings in combinator conditions.
+#[derive(PartialEq)]
+enum Wildcard {
+    Each,
+    Anything,
+    Everything
+}
+
+fn wildcard(signal: &Signal) -> Option<Wildcard> {
+    if signal.cat != "virtual" {
+        return None;
+    }
+    match signal.name.as_str() {
+        "signal-each" => Some(Wildcard::Each),
+        "signal-anything" => Some(Wildcard::Anything),
+        "signal-everything" => Some(Wildcard::Everything),
+        _ => None
+    }
+}
+
+/// Reads an operation from a blueprint. Deciders exported by the game use unicode comparators.
+fn parse_operation(op: &str) -> BinOp {
+    match op {
+        "+" => BinOp::Add,
+        "-" => BinOp::Sub,
+        "*" => BinOp::Mul,
+        "/" => BinOp::Div,
+        "%" => BinOp::Mod,
+        "^" => BinOp::Power,
+        "<<" => BinOp::ShiftLeft,
+        ">>" => BinOp::ShiftRight,
+        "AND" => BinOp::BitAnd,
+        "OR" => BinOp::BitOr,
+        "XOR" => BinOp::BitXor,
+        "=" => BinOp::CmpEq,
+        "!=" | "≠" => BinOp::CmpNeq,
+        "<" => BinOp::CmpLt,
+        ">" => BinOp::CmpGt,
+        "<=" | "≤" => BinOp::CmpLeq,
+        ">=" | "≥" => BinOp::CmpGeq,
+        _ => panic!("Unknown combinator operation '{}'.",op)
+    }
+}
+
+fn color_index(color: WireColor) -> usize {
+    match color {
+        WireColor::Red => 0,
+        WireColor::Green => 1,
+        _ => panic!("A wire must be red or green.")
+    }
+}
+
+/// Simulates any blueprint made of combinators.
+///
+/// Wires that touch are joined into networks, which carry the sum of every signal output onto them.
+/// Every combinator takes one tick to update its output. Constant combinators output their filters,
+/// which can be changed to drive inputs. Entities other than combinators only connect wires.
+pub struct BlueprintSim<'b> {
+    blueprint: &'b Blueprint,
+    entity_index: HashMap<u32,usize>,
+    /// The network each wire connection point is part of, or None if no wire is connected.
+    /// Indexed by `terminal`.
+    terminal_nets: Vec<Option<usize>>,
+    /// The signals each entity outputs.
+    outputs: Vec<SignalMap>,
+    /// The signals on each network for the current tick.
+    networks: Vec<SignalMap>
+}
+
+/// Each entity has two circuits (input and output), each of which can be wired with either color.
+fn terminal(entity_index: usize, circuit_id: u32, color: WireColor) -> usize {
+    let circuit = if circuit_id == 2 { 1 } else { 0 };
+    entity_index * 4 + circuit * 2 + color_index(color)
+}
+
+/// The circuit that entities output signals on. Constant combinators only have one.
+fn output_circuit(entity: &Entity) -> Option<u32> {
+    match entity.name.as_str() {
+        "constant-combinator" => Some(1),
+        "arithmetic-combinator" | "decider-combinator" => Some(2),
+        _ => None
+    }
+}
+
+impl<'b> BlueprintSim<'b> {
+    pub fn new(blueprint: &'b Blueprint) -> Self {
+        let entity_index: HashMap<u32,usize> = blueprint.entities.iter().enumerate()
+            .map(|(i,ent)| (ent.entity_number,i)).collect();
+
+        let terminal_count = blueprint.entities.len() * 4;
+        let mut sets = DisjointSet::new(terminal_count);
+        let mut wired = vec![false; terminal_count];
+        for (i,ent) in blueprint.entities.iter().enumerate() {
+            for (circuit_id,connections) in ent.connections.iter().flatten() {
+                for (color,list) in [(WireColor::Red,&connections.red),(WireColor::Green,&connections.green)] {
+                    for conn in list.iter().flatten() {
+                        let other = *entity_index.get(&conn.entity_id)
+                            .unwrap_or_else(|| panic!("Entity {} is wired to missing entity {}.",ent.entity_number,conn.entity_id));
+                        let a = terminal(i,*circuit_id,color);
+                        let b = terminal(other,conn.circuit_id.unwrap_or(1),color);
+                        wired[a] = true;
+                        wired[b] = true;
+                        sets.merge(a,b);
+                    }
+                }
+            }
+        }
+
+        let mut net_ids = HashMap::new();
+        let terminal_nets = (0..terminal_count).map(|t| {
+            if wired[t] {
+                let root = sets.get(t);
+                let next_id = net_ids.len();
+                Some(*net_ids.entry(root).or_insert(next_id))
+            } else {
+                None
+            }
+        }).collect();
+
+        let mut sim = BlueprintSim{
+            blueprint,
+            entity_index,
+            terminal_nets,
+            outputs: Vec::new(),
+            networks: vec![SignalMap::new(); net_ids.len()]
+        };
+        sim.reset();
+        sim
+    }
+
+    /// Clears all combinator outputs and restores constant combinators to their filters.
+    pub fn reset(&mut self) {
+        self.outputs = self.blueprint.entities.iter().map(|ent| {
+            let mut map = SignalMap::new();
+            if ent.name == "constant-combinator" {
+                for filter in ent.control_behavior.filters.iter().flatten() {
+                    add_signal(&mut map,&filter.signal,filter.count);
+                }
+            }
+            map
+        }).collect();
+        self.settle();
+    }
+
+    fn get_index(&self, entity_number: u32) -> usize {
+        *self.entity_index.get(&entity_number).unwrap_or_else(|| panic!("No entity {} in blueprint.",entity_number))
+    }
+
+    /// Sets the value a constant combinator outputs for a signal. Used to drive inputs.
+    pub fn set_constant(&mut self, entity_number: u32, signal: &Signal, value: i32) {
+        let index = self.get_index(entity_number);
+        let map = &mut self.outputs[index];
+        map.remove(signal);
+        add_signal(map,signal,value);
+        self.settle();
+    }
+
+    /// The value of a signal on a wire connected to an entity.
+    pub fn get_signal(&self, entity_number: u32, circuit_id: u32, color: WireColor, signal: &Signal) -> i32 {
+        let index = self.get_index(entity_number);
+        self.terminal_nets[terminal(index,circuit_id,color)]
+            .and_then(|net| self.networks[net].get(signal).copied())
+            .unwrap_or(0)
+    }
+
+    /// Runs a single tick. Every combinator reads its inputs and updates its output.
+    pub fn step(&mut self) {
+        for (i,ent) in self.blueprint.entities.iter().enumerate() {
+            let conditions = &ent.control_behavior;
+            let output = match ent.name.as_str() {
+                "arithmetic-combinator" => conditions.arithmetic_conditions.as_ref().map(|cond| arithmetic(cond,&self.input(i))),
+                "decider-combinator" => conditions.decider_conditions.as_ref().map(|cond| decider(cond,&self.input(i))),
+                _ => continue
+            };
+            self.outputs[i] = output.unwrap_or_default();
+        }
+        self.settle();
+    }
+
+    /// The sum of both wires on a combinator's input.
+    fn input(&self, index: usize) -> SignalMap {
+        let mut res = SignalMap::new();
+        for color in [WireColor::Red,WireColor::Green] {
+            if let Some(net) = self.terminal_nets[terminal(index,1,color)] {
+                for (signal,value) in &self.networks[net] {
+                    add_signal(&mut res,signal,*value);
+                }
+            }
+        }
+        res
+    }
+
+    /// Sums the outputs of every entity onto the networks they are wired to.
+    fn settle(&mut self) {
+        self.networks.iter_mut().for_each(|net| net.clear());
+        for (i,ent) in self.blueprint.entities.iter().enumerate() {
+            if let Some(circuit_id) = output_circuit(ent) {
+                for color in [WireColor::Red,WireColor::Green] {
+                    if let Some(net) = self.terminal_nets[terminal(i,circuit_id,color)] {
+                        for (signal,value) in &self.outputs[i] {
+                            add_signal(&mut self.networks[net],signal,*value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The value of an operand, which may be a signal or a constant. Missing operands are zero.
+fn operand(signal: &Option<Signal>, constant: Option<i32>, input: &SignalMap) -> i32 {
+    match signal {
+        Some(signal) => input.get(signal).copied().unwrap_or(0),
+        None => constant.unwrap_or(0)
+    }
+}
+
+fn arithmetic(cond: &ArithmeticConditions, input: &SignalMap) -> SignalMap {
+    let op = parse_operation(&cond.operation);
+    let first_each = cond.first_signal.as_ref().and_then(wildcard) == Some(Wildcard::Each);
+    let second_each = cond.second_signal.as_ref().and_then(wildcard) == Some(Wildcard::Each);
+    let mut res = SignalMap::new();
+    let output = match &cond.output_signal {
+        Some(signal) => signal,
+        None => return res
+    };
+
+    if first_each || second_each {
+        // Each input signal is computed separately. Results are output on their own signals, or summed.
+        let output_each = wildcard(output) == Some(Wildcard::Each);
+        for (signal,value) in input {
+            let lhs = if first_each { *value } else { operand(&cond.first_signal,cond.first_constant,input) };
+            let rhs = if second_each { *value } else { operand(&cond.second_signal,cond.second_constant,input) };
+            let result = op.fold(lhs,rhs);
+            add_signal(&mut res,if output_each { signal } else { output },result);
+        }
+    } else {
+        let lhs = operand(&cond.first_signal,cond.first_constant,input);
+        let rhs = operand(&cond.second_signal,cond.second_constant,input);
+        add_signal(&mut res,output,op.fold(lhs,rhs));
+    }
+    res
+}
+
+fn decider(cond: &DeciderConditions, input: &SignalMap) -> SignalMap {
+    let op = parse_operation(&cond.comparator);
+    let mut res = SignalMap::new();
+    let (first,output) = match (&cond.first_signal,&cond.output_signal) {
+        (Some(first),Some(output)) => (first,output),
+        _ => return res
+    };
+    let rhs = operand(&cond.second_signal,cond.constant,input);
+    let check = |value: i32| op.fold(value,rhs) != 0;
+    let output_value = |value: i32| if cond.copy_count_from_input { value } else { 1 };
+
+    let matching: Vec<(&Signal,i32)> = input.iter().filter(|(_,value)| check(**value)).map(|(s,v)| (s,*v)).collect();
+    let passed = match wildcard(first) {
+        Some(Wildcard::Each) => {
+            // Each matching signal passes on its own. Results are output on their own signals, or summed.
+            let output_each = wildcard(output) == Some(Wildcard::Each);
+            for (signal,value) in matching {
+                add_signal(&mut res,if output_each { signal } else { output },output_value(value));
+            }
+            return res;
+        },
+        Some(Wildcard::Anything) => !matching.is_empty(),
+        Some(Wildcard::Everything) => matching.len() == input.len(),
+        None => check(input.get(first).copied().unwrap_or(0))
+    };
+
+    if passed {
+        match wildcard(output) {
+            Some(Wildcard::Everything) => {
+                for (signal,value) in input {
+                    add_signal(&mut res,signal,output_value(*value));
+                }
+            },
+            Some(Wildcard::Anything) => {
+                // Only one signal is output, preferring one that matched the condition.
+                if let Some((signal,value)) = matching.first().copied().or_else(|| input.iter().next().map(|(s,v)| (s,*v))) {
+                    add_signal(&mut res,signal,output_value(value));
+                }
+            },
+            Some(Wildcard::Each) => (),
+            None => add_signal(&mut res,output,output_value(input.get(output).copied().unwrap_or(0)))
+        }
+    }
+    res
+}
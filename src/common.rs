@@ -39,7 +39,7 @@ impl BinOp {
         }
     }
 
-    pub fn to_str(&self) -> &'static str {
+    pub fn to_str(self) -> &'static str {
         match self {
             BinOp::Add => "+",
             BinOp::Sub => "-",
@@ -65,16 +65,24 @@ impl BinOp {
         }
     }
 
+    /// Computes the result the way a combinator would in game.
+    /// Overflow wraps, and dividing by zero produces zero instead of an error.
     pub fn fold(&self, lhs: i32, rhs: i32) -> i32 {
         match self {
             BinOp::Add => lhs.wrapping_add(rhs),
             BinOp::Sub => lhs.wrapping_sub(rhs),
             BinOp::Mul => lhs.wrapping_mul(rhs),
+            BinOp::Div | BinOp::Mod if rhs == 0 => 0,
             BinOp::Div => lhs.wrapping_div(rhs),
-            BinOp::Mod => lhs % rhs,
+            BinOp::Mod => lhs.wrapping_rem(rhs),
 
             BinOp::BitOr => lhs | rhs,
             BinOp::BitAnd => lhs & rhs,
+            BinOp::BitXor => lhs ^ rhs,
+
+            // Shift amounts wrap at 32 bits, and right shifts are arithmetic.
+            BinOp::ShiftLeft => lhs.wrapping_shl(rhs as u32),
+            BinOp::ShiftRight => lhs.wrapping_shr(rhs as u32),
 
             BinOp::CmpEq => if lhs == rhs { 1 } else { 0 },
             BinOp::CmpNeq => if lhs != rhs { 1 } else { 0 },
@@ -83,21 +91,46 @@ impl BinOp {
             BinOp::CmpLeq => if lhs <= rhs { 1 } else { 0 },
             BinOp::CmpGeq => if lhs >= rhs { 1 } else { 0 },
 
+            // The game gives zero for negative exponents.
             BinOp::Power => if rhs < 0 {
-                panic!("negative pow")
+                0
             } else {
                 lhs.wrapping_pow(rhs as u32)
-            },
-            _ => panic!("todo fold {:?}",self)
+            }
         }
     }
 
+    /// Like fold, but reports overflow and other bad operations instead of wrapping.
+    /// Used for constant expressions, where these are almost certainly mistakes.
+    pub fn checked_fold(&self, lhs: i32, rhs: i32) -> Result<i32,String> {
+        let res = match self {
+            BinOp::Add => lhs.checked_add(rhs),
+            BinOp::Sub => lhs.checked_sub(rhs),
+            BinOp::Mul => lhs.checked_mul(rhs),
+            BinOp::Div | BinOp::Mod if rhs == 0 => {
+                return Err(format!("Division by zero: {} {} {}",lhs,self.to_str(),rhs));
+            },
+            BinOp::Div => lhs.checked_div(rhs),
+            BinOp::Mod => lhs.checked_rem(rhs),
+            BinOp::Power => if rhs < 0 {
+                return Err(format!("Negative exponent: {} ** {}",lhs,rhs));
+            } else {
+                lhs.checked_pow(rhs as u32)
+            },
+            BinOp::ShiftLeft | BinOp::ShiftRight if !(0..32).contains(&rhs) => {
+                return Err(format!("Shift amount out of range: {} {} {}",lhs,self.to_str(),rhs));
+            },
+            // Shifts are bit operations, so shifting into the sign bit is not an overflow.
+            _ => Some(self.fold(lhs,rhs))
+        };
+        res.ok_or_else(|| format!("Overflow in constant expression: {} {} {}",lhs,self.to_str(),rhs))
+    }
+
     pub fn is_compare(&self) -> bool {
-        match self {
+        matches!(self,
             BinOp::CmpLt | BinOp::CmpGt | BinOp::CmpLeq | BinOp::CmpGeq |
-            BinOp::CmpEq | BinOp::CmpNeq => true,
-            _ => false
-        }
+            BinOp::CmpEq | BinOp::CmpNeq
+        )
     }
 
     pub fn flip(&self) -> BinOp {
@@ -150,3 +183,31 @@ pub enum ConnectType {
     In,
     Out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_fold_overflow() {
+        assert_eq!(BinOp::Add.checked_fold(1,2),Ok(3));
+        assert!(BinOp::Add.checked_fold(i32::MAX,1).is_err());
+        assert!(BinOp::Sub.checked_fold(i32::MIN,1).is_err());
+        assert!(BinOp::Mul.checked_fold(0x10000,0x10000).is_err());
+        assert!(BinOp::Div.checked_fold(i32::MIN,-1).is_err());
+        assert!(BinOp::Power.checked_fold(2,31).is_err());
+        assert_eq!(BinOp::Power.checked_fold(2,30),Ok(1 << 30));
+        // Shifting into the sign bit is allowed, shifting by the word size or more is not.
+        assert_eq!(BinOp::ShiftLeft.checked_fold(1,31),Ok(i32::MIN));
+        assert!(BinOp::ShiftLeft.checked_fold(1,32).is_err());
+        assert!(BinOp::ShiftRight.checked_fold(1,-1).is_err());
+    }
+
+    #[test]
+    fn checked_fold_division_by_zero() {
+        assert_eq!(BinOp::Div.checked_fold(7,0),Err("Division by zero: 7 / 0".to_owned()));
+        assert!(BinOp::Mod.checked_fold(7,0).is_err());
+        assert_eq!(BinOp::Div.checked_fold(-7,2),Ok(-3));
+        assert_eq!(BinOp::Mod.checked_fold(-7,2),Ok(-1));
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt::Write;
+
+/// A range of bytes in a source file.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span{start,end}
+    }
+
+    /// A span covering both this span and another one that comes after it.
+    pub fn to(self, other: Span) -> Span {
+        Span{start: self.start, end: other.end.max(self.end)}
+    }
+}
+
+/// An error tied to a location in the source, rendered with the offending line.
+#[derive(Debug,Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub label: String
+}
+
+impl Diagnostic {
+    pub fn new(message: String, span: Span) -> Self {
+        Diagnostic{message,span,label: String::new()}
+    }
+
+    pub fn with_label(mut self, label: String) -> Self {
+        self.label = label;
+        self
+    }
+
+    /// Renders the diagnostic in a rustc-like format:
+    ///
+    /// ```text
+    /// error: Expected `;`, found identifier `x`.
+    ///   --> projects/test.cdl:3:5
+    ///    |
+    ///  3 |     x = 5
+    ///    |     ^
+    /// ```
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let line_end = source[start..].find('\n').map(|x| x + start).unwrap_or(source.len());
+        let line = source[line_start..line_end].trim_end_matches('\r');
+
+        let (line_num,col_num) = line_col(source,start);
+
+        // Copy whitespace from the line itself so tabs line up with the caret.
+        let caret_pad: String = source[line_start..start].chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let caret_end = self.span.end.min(line_start + line.len()).max(start);
+        let caret_count = source[start..caret_end].chars().count().max(1);
+
+        let gutter = line_num.to_string().len();
+        let blank = " ".repeat(gutter);
+
+        let mut out = String::new();
+        writeln!(out,"error: {}",self.message).unwrap();
+        writeln!(out,"{}--> {}:{}:{}",blank,file_name,line_num,col_num).unwrap();
+        writeln!(out,"{} |",blank).unwrap();
+        writeln!(out,"{} | {}",line_num,line).unwrap();
+        write!(out,"{} | {}{}",blank,caret_pad,"^".repeat(caret_count)).unwrap();
+        if !self.label.is_empty() {
+            write!(out," {}",self.label).unwrap();
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// The line and column of a byte offset, both starting at 1. Columns count characters, not bytes.
+pub fn line_col(source: &str, offset: usize) -> (usize,usize) {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset].rfind('\n').map(|x| x + 1).unwrap_or(0);
+    (source[..line_start].matches('\n').count() + 1,source[line_start..offset].chars().count() + 1)
+}
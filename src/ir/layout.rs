@@ -20,7 +20,7 @@ fn square_dist(a: (f32,f32), b: (f32,f32)) -> f32 {
 }
 
 fn check_dist(sq_dist: f32) -> bool {
-    return sq_dist <= MAX_DIST * MAX_DIST;
+    sq_dist <= MAX_DIST * MAX_DIST
 }
 
 impl WireNet {
@@ -184,6 +184,11 @@ struct NetRegistry {
 
 impl NetRegistry {
     fn add_link(&mut self, src_arg: &IRArg, dest_id: u32, module: &IRModule) {
+        if let IRArg::Link(src_id,WireColor::Both) = src_arg {
+            self.add_link(&IRArg::Link(*src_id,WireColor::Red), dest_id, module);
+            self.add_link(&IRArg::Link(*src_id,WireColor::Green), dest_id, module);
+            return;
+        }
         if let IRArg::Link(src_id,color) = src_arg {            
             if let IRNode::MultiDriver(args) = module.nodes.get(*src_id as usize) {
                 for arg in args {
@@ -248,7 +253,7 @@ impl NetRegistry {
         }
 
         // quick exit
-        if failed.len() > 0 {
+        if !failed.is_empty() {
             return Err(failed);
         }
         out.clear();
@@ -259,7 +264,7 @@ impl NetRegistry {
             }
         }
 
-        if failed.len() > 0 {
+        if !failed.is_empty() {
             Err(failed)
         } else {
             Ok(out)
@@ -291,7 +296,7 @@ impl Grid {
     }
 
     fn is_cell_filled(&self, key: (i32,i32)) -> bool {
-        self.cell_map.get(&key).is_some()
+        self.cell_map.contains_key(&key)
     }
 
     fn is_cell_reserved(&self, key: (i32,i32)) -> bool {
@@ -301,7 +306,7 @@ impl Grid {
     }
 
     fn get_id_at(&self, key: (i32,i32)) -> Option<u32> {
-        self.cell_map.get(&key).map(|x| *x)
+        self.cell_map.get(&key).copied()
     }
 
     fn set(&mut self, key: (i32,i32), val: u32) {
@@ -408,7 +413,7 @@ impl IRModule {
         let mut priority_list = Vec::new();
         loop {
             // Try to generate links
-            let res = networks.to_links(&self, &priority_list);
+            let res = networks.to_links(self, &priority_list);
             //let err_count = res.as_ref().err().map(|list| list.len()).unwrap_or(0);
 
             if let Err(bad_nets) = res {
@@ -426,10 +431,9 @@ impl IRModule {
     }
 
     fn can_move(&self, id: u32) -> bool {
-        match self.nodes.get(id as usize) {
+        !matches!(self.nodes.get(id as usize),
             IRNode::Input(..) |
-            IRNode::Output(..) => false,
-            _ => true
-        }
+            IRNode::Output(..)
+        )
     }
 }
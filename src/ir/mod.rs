@@ -1,23 +1,45 @@
 use std::{collections::HashMap, rc::Rc};
-use std::convert::TryInto;
+use std::convert::{Infallible, TryInto};
 
 use crate::{CompileSettings, common::{BinOp, UnaryOp}};
-use crate::parser::{Expr, ParseItem, Statement};
+use crate::diagnostic::{Diagnostic, Span, line_col};
+use crate::loader::SourceFile;
+use crate::symbols::SymbolTable;
+use crate::parser::{Binding, Expr, ItemPath, MatchArm, Module, ParseItem, PortColor, Statement};
 
 use self::layout::{Grid, WireLink};
+use self::scope::{ModuleRef, Scope};
+pub use self::scope::{ModuleTable, Namespace};
+pub use self::repl::Repl;
+pub use self::sim::Simulator;
+pub use self::to_blueprint::BlueprintPorts;
 
+mod scope;
 mod select_colors;
 mod select_symbols;
 mod layout;
 mod to_blueprint;
 mod opt;
+mod sim;
+mod repl;
+mod testbench;
+mod vcd;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 #[derive(Debug)]
 pub struct IRModule {
     name: String,
+    /// The file the module is declared in.
+    file: usize,
+    public: bool,
     settings: Rc<CompileSettings>,
     port_count: i32,
     bindings: HashMap<String,IRArg>,
+    /// Lengths of arrays. Array elements are stored in `bindings` as `name[index]`.
+    arrays: HashMap<String,u32>,
+    /// How many times each submodule has been inlined, used to tell instances apart in debug names.
+    instance_counts: HashMap<String,u32>,
     nodes: NodeList,
     outputs_set: bool,
     out_symbols: Vec<u32>,
@@ -26,7 +48,10 @@ pub struct IRModule {
 
     // copied straight from the parse module
     arg_types: Vec<Option<u32>>,
-    ret_types: Option<Vec<Option<u32>>>
+    ret_types: Option<Vec<Option<u32>>>,
+    /// Wire colors of the ports, which default to red.
+    arg_colors: Vec<WireColor>,
+    ret_colors: Vec<WireColor>
 }
 
 #[derive(Debug,Clone,PartialEq)]
@@ -55,7 +80,7 @@ enum IRArg {
 
 impl IRArg {
     fn is_link(&self) -> bool {
-        return if let IRArg::Link(..) = self { true } else { false };
+        matches!(self, IRArg::Link(..))
     }
 
     /*fn is_const(&self) -> bool {
@@ -67,56 +92,160 @@ impl IRArg {
 pub enum WireColor {
     Red,
     Green,
+    /// Connected with both wires. Only used for `@both` inputs.
+    Both,
     None
 }
 
+/// The nodes of a module, each with a name for debugging.
 #[derive(Default,Debug)]
 struct NodeList {
     nodes: Vec<IRNode>,
-    debug_names: Vec<String>
+    names: Vec<NodeName>,
+    /// Where each node came from, if known. Nodes inlined from a submodule keep their location in its file.
+    locations: Vec<Option<Location>>,
+    /// The location given to new nodes, which is the statement being built.
+    current_location: Option<Location>
 }
 
-impl NodeList {
-    pub fn iter(&self) -> core::slice::Iter<IRNode> {
-        self.nodes.iter()
+/// A place in a source file. The file is an index into the loaded files, like in `Namespace`.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Location {
+    pub file: usize,
+    pub span: Span
+}
+
+impl Location {
+    /// Formats the location as `file:line:col`.
+    pub fn describe(&self, files: &[SourceFile]) -> String {
+        let file = &files[self.file];
+        let (line,col) = line_col(file.text,self.span.start);
+        format!("{}:{}:{}",file.name,line,col)
+    }
+}
+
+/// Describes a node in errors and traces.
+///
+/// Debug names of module arguments look like `arg x`, and nodes bound with `let` look like `x = ...`.
+/// Nodes inlined from a submodule are prefixed with the instance they came from, like `[cell#0] x = ...`.
+#[derive(Debug,Clone,Default)]
+struct NodeName {
+    /// The submodule instances the node was inlined through, outermost first, like `cell#0`.
+    instances: Vec<String>,
+    /// The argument or binding that holds the node's value, like `x` or `V[3]`.
+    binding: Option<String>,
+    is_arg: bool,
+    /// What the node computes, like `BinOp(...)` or `if-merge`. Not shown for arguments.
+    detail: String
+}
+
+impl NodeName {
+    fn new(detail: String) -> Self {
+        NodeName{detail, ..Default::default()}
     }
 
-    pub fn iter_mut(&mut self) -> core::slice::IterMut<IRNode> {
-        self.nodes.iter_mut()
+    fn arg(name: String) -> Self {
+        NodeName{binding: Some(name), is_arg: true, ..Default::default()}
     }
+}
 
-    pub fn iter_debug(&self) -> std::iter::Zip<core::slice::Iter<IRNode>,core::slice::Iter<String>> {
-        self.nodes.iter().zip(self.debug_names.iter())
+impl std::fmt::Display for NodeName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for instance in &self.instances {
+            write!(f,"[{}] ",instance)?;
+        }
+        match &self.binding {
+            Some(binding) if self.is_arg => write!(f,"arg {}",binding),
+            Some(binding) => write!(f,"{} = {}",binding,self.detail),
+            None => write!(f,"{}",self.detail)
+        }
+    }
+}
+
+impl NodeList {
+    pub fn iter(&self) -> core::slice::Iter<'_, IRNode> {
+        self.nodes.iter()
+    }
+
+    pub fn iter_named(&self) -> std::iter::Zip<core::slice::Iter<'_, IRNode>,core::slice::Iter<'_, NodeName>> {
+        self.nodes.iter().zip(self.names.iter())
     }
 
     pub fn get(&self, index: usize) -> &IRNode {
-        return &self.nodes[index];
+        &self.nodes[index]
     }
 
     pub fn get_mut(&mut self, index: usize) -> &mut IRNode {
-        return &mut self.nodes[index];
+        &mut self.nodes[index]
     }
 
-    pub fn get_debug(&self, index: usize) -> &str {
-        return &self.debug_names[index];
+    pub fn get_debug(&self, index: usize) -> String {
+        self.names[index].to_string()
+    }
+
+    /// Describes a node in messages by its name, if it is bound to one, and where it comes from.
+    /// Nodes without a location are described by their debug name.
+    pub fn describe(&self, index: usize, files: &[SourceFile]) -> String {
+        let name = &self.names[index];
+        let location = match self.locations[index] {
+            Some(location) => location.describe(files),
+            None => return format!("({})",name)
+        };
+        let instances = if name.instances.is_empty() {
+            String::new()
+        } else {
+            format!(" (in {})",name.instances.join(" > "))
+        };
+        match &name.binding {
+            Some(binding) => format!("`{}`{} at {}",binding,instances,location),
+            None => format!("the value{} at {}",instances,location)
+        }
+    }
+
+    /// The source-level name of an argument or bound node, along with the submodule instances
+    /// it was inlined from, outermost first.
+    pub fn binding_name(&self, index: usize) -> Option<(&[String],&str)> {
+        let name = &self.names[index];
+        name.binding.as_deref().map(|binding| (&name.instances[..],binding))
     }
 
     pub fn try_get(&self, index: usize) -> Option<&IRNode> {
-        return self.nodes.get(index);
+        self.nodes.get(index)
     }
 
     pub fn len(&self) -> usize {
         self.nodes.len()
     }
 
-    pub fn push(&mut self, node: IRNode, name: String) {
+    pub fn push(&mut self, node: IRNode, detail: String) {
+        self.push_at(node, NodeName::new(detail), self.current_location);
+    }
+
+    pub fn push_at(&mut self, node: IRNode, name: NodeName, location: Option<Location>) {
         self.nodes.push(node);
-        self.debug_names.push(name);
+        self.names.push(name);
+        self.locations.push(location);
+    }
+
+    pub fn location(&self, index: usize) -> Option<Location> {
+        self.locations[index]
     }
 
-    pub fn set(&mut self, index: usize, node: IRNode, name: String) {
+    pub fn set_location(&mut self, index: usize, location: Option<Location>) {
+        self.locations[index] = location;
+    }
+
+    pub fn set_current_location(&mut self, location: Option<Location>) {
+        self.current_location = location;
+    }
+
+    pub fn set(&mut self, index: usize, node: IRNode, detail: String) {
         self.nodes[index] = node;
-        self.debug_names[index] = name;
+        self.names[index] = NodeName::new(detail);
+    }
+
+    pub fn set_binding(&mut self, index: usize, binding: String) {
+        self.names[index].binding = Some(binding);
     }
 
     pub fn update(&mut self, index: usize, node: IRNode) {
@@ -128,9 +257,13 @@ impl IRModule {
     fn new(name: String, settings: Rc<CompileSettings>) -> Self {
         IRModule{
             name,
+            file: 0,
+            public: false,
             settings,
             port_count: 0,
             bindings: HashMap::new(),
+            arrays: HashMap::new(),
+            instance_counts: HashMap::new(),
             nodes: Default::default(),
             outputs_set: false,
             out_symbols: Vec::new(),
@@ -138,18 +271,26 @@ impl IRModule {
             links: Vec::new(),
 
             arg_types: Vec::new(),
-            ret_types: None
+            ret_types: None,
+            arg_colors: Vec::new(),
+            ret_colors: Vec::new()
         }
     }
 
     #[allow(unused)]
-    pub fn print(&self) {
+    pub fn print(&self, symbols: &SymbolTable) {
         println!("IR MODULE: {}",self.name);
+        let types: Vec<String> = self.arg_types.iter().map(|ty| ty.map(|sym| symbols.symbol_name(sym)).unwrap_or_else(|| "_".to_owned())).collect();
+        println!("ARG TYPES: {}",types.join(", "));
+        if let Some(ret_types) = &self.ret_types {
+            let types: Vec<String> = ret_types.iter().map(|ty| ty.map(|sym| symbols.symbol_name(sym)).unwrap_or_else(|| "_".to_owned())).collect();
+            println!("RET TYPES: {}",types.join(", "));
+        }
         println!("NODES:");
         for (i,node) in self.nodes.iter().enumerate() {
             let pos = self.get_true_pos(i as u32 );
-            let symbol = self.out_symbols.get(i);
-            println!("    {}: {:?}, pos = {:?}, symbol = {:?}",i,node,pos,symbol);
+            let symbol = self.out_symbols.get(i).map(|sym| symbols.symbol_name(*sym));
+            println!("    {}: {:?}, pos = {:?}, symbol = {}",i,node,pos,symbol.as_deref().unwrap_or("-"));
         }
         println!("LINKS:");
         for link in &self.links {
@@ -157,6 +298,15 @@ impl IRModule {
         }
     }
 
+    /// The wire color a port node is connected to the outside world with. None for other nodes.
+    fn port_color(&self, id: u32) -> Option<WireColor> {
+        match self.nodes.get(id as usize) {
+            IRNode::Input(n) => Some(self.arg_colors[*n as usize]),
+            IRNode::Output(n,_) => Some(self.ret_colors.get(*n as usize).copied().unwrap_or(WireColor::Red)),
+            _ => None
+        }
+    }
+
     fn get_true_pos(&self, id: u32) -> Option<(f32,f32)> {
         self.grid.get_pos_for(id).map(|pos|{
             let x = pos.0 as f32;
@@ -175,78 +325,287 @@ impl IRModule {
         })
     }
 
-    fn add_args(&mut self, arg_names: &Vec<&str>) {
-        self.port_count += arg_names.len() as i32;
-        for (i,arg_name) in arg_names.iter().enumerate() {
-            self.nodes.push(IRNode::Input(i as u32),format!("arg {}",self.name));
-            if self.bindings.insert((*arg_name).to_owned(), IRArg::Link(i as u32,WireColor::None) ).is_some() {
-                panic!("Module '{}': Duplicate argument '{}'.",self.name,arg_name);
+    /// Adds an input port for every argument, or for every element of an array argument.
+    fn add_args(&mut self, p_mod: &Module, scope: &Scope) {
+        for ((((arg_name,arg_size),arg_type),arg_color),arg_span) in p_mod.arg_names.iter().zip(&p_mod.arg_sizes).zip(&p_mod.arg_types).zip(&p_mod.arg_colors).zip(&p_mod.arg_spans) {
+            let names = if let Some(size) = arg_size {
+                let len = self.array_len(arg_name, size, scope);
+                self.arrays.insert((*arg_name).to_owned(), len);
+                (0..len).map(|i| format!("{}[{}]",arg_name,i)).collect()
+            } else {
+                vec!((*arg_name).to_owned())
+            };
+
+            for name in names {
+                let i = self.nodes.len() as u32;
+                self.nodes.push_at(IRNode::Input(i),NodeName::arg(name.clone()),Some(self.location(*arg_span)));
+                self.arg_types.push(*arg_type);
+                self.arg_colors.push(port_color(*arg_color));
+                if self.bindings.insert(name.clone(), IRArg::Link(i,WireColor::None) ).is_some() {
+                    panic!("Module '{}': Duplicate argument '{}'.",self.name,name);
+                }
             }
         }
+        self.port_count += self.nodes.len() as i32;
+    }
+
+    /// Copies the return types, repeating the type and color of array outputs for every element.
+    fn add_ret_types(&mut self, p_mod: &Module, scope: &Scope) {
+        let ret_types = match &p_mod.ret_types {
+            Some(ret_types) => ret_types,
+            None => return
+        };
+        let mut types = Vec::new();
+        for ((ret_type,ret_size),ret_color) in ret_types.iter().zip(&p_mod.ret_sizes).zip(&p_mod.ret_colors) {
+            let len = match ret_size {
+                Some(size) => self.array_len("output", size, scope),
+                None => 1
+            };
+            for _ in 0..len {
+                types.push(*ret_type);
+                self.ret_colors.push(port_color(*ret_color));
+            }
+        }
+        self.ret_types = Some(types);
+    }
+
+    fn array_len(&self, name: &str, size: &Expr, scope: &Scope) -> u32 {
+        let len = self.eval_constant(size, scope);
+        len.try_into().unwrap_or_else(|_| panic!("Module '{}': Array '{}' has invalid length {}.",self.name,name,len))
+    }
+
+    fn location(&self, span: Span) -> Location {
+        Location{file: self.file, span}
     }
 
     /// Run in its own pass before add_stmt
-    fn add_stmt_bindings(&mut self, stmt: &Statement) {
-        match stmt {
-            Statement::VarBinding(idents,_expr) => {
-                for var_name in idents {
-                    self.nodes.push(IRNode::PlaceHolder,"placeholder".to_owned());
-                    let arg = IRArg::Link(self.nodes.len() as u32 - 1, WireColor::None);
+    fn add_stmt_bindings(&mut self, stmt: &Statement, scope: &Scope) -> Result<(),Diagnostic> {
+        if let Some(span) = stmt.span() {
+            self.nodes.set_current_location(Some(self.location(span)));
+        }
+        let names = match stmt {
+            Statement::VarBinding(idents,expr,span) => {
+                let names = self.binding_names(idents, scope);
+                self.check_value_count(names.len(), expr, *span, scope).map_err(|err| {
+                    if idents.iter().any(|ident| ident.index.is_some()) {
+                        err.with_label("elements of an array declared with `let V[N];` are assigned without `let`".to_owned())
+                    } else {
+                        err
+                    }
+                })?;
+                names
+            },
+            Statement::ArrayDecl(name,size,_) => {
+                let len = self.array_len(name, size, scope);
+                self.arrays.insert((*name).to_owned(), len);
+                (0..len).map(|i| format!("{}[{}]",name,i)).collect()
+            },
+            Statement::For(var,start,end,body) => {
+                return self.unroll(var, start, end, scope, |ir,scope| {
+                    body.iter().try_for_each(|stmt| ir.add_stmt_bindings(stmt, scope))
+                });
+            },
+            _ => return Ok(())
+        };
+
+        for var_name in names {
+            self.nodes.push(IRNode::PlaceHolder,"placeholder".to_owned());
+            let arg = IRArg::Link(self.nodes.len() as u32 - 1, WireColor::None);
+
+            if self.bindings.insert(var_name.clone(), arg ).is_some() {
+                panic!("Module '{}': Duplicate variable binding '{}'.",self.name,var_name);
+            }
+        }
+        Ok(())
+    }
+
+    /// The keys a `let` statement binds, in order. An indexed target like `rows[8]` binds a whole array.
+    fn binding_names(&mut self, idents: &[Binding], scope: &Scope) -> Vec<String> {
+        let mut names = Vec::new();
+        for ident in idents {
+            if let Some(size) = &ident.index {
+                let len = self.array_len(ident.name, size, scope);
+                self.arrays.insert(ident.name.to_owned(), len);
+                names.extend((0..len).map(|i| format!("{}[{}]",ident.name,i)));
+            } else {
+                names.push(ident.name.to_owned());
+            }
+        }
+        names
+    }
+
+    /// Checks that the right hand side of a `let` or an assignment produces one value for each target.
+    fn check_value_count(&mut self, target_count: usize, expr: &Expr, span: Span, scope: &Scope) -> Result<(),Diagnostic> {
+        let value_count = if let Expr::SubModule(path,params,_) = expr {
+            self.find_submodule(scope, path, params).output_count()
+        } else {
+            1
+        };
+        if target_count != value_count {
+            return Err(Diagnostic::new(format!("The bindings take {} values, but the right hand side produces {}.",target_count,value_count),span));
+        }
+        Ok(())
+    }
 
-                    if self.bindings.insert((*var_name).to_owned(), arg ).is_some() {
-                        panic!("Module '{}': Duplicate variable binding '{}'.",self.name,var_name);
+    /// The keys an assignment stores to. Each one must be an element of a declared array that has not been assigned yet.
+    fn assignment_names(&self, targets: &[Binding], scope: &Scope) -> Result<Vec<String>,Diagnostic> {
+        let mut names: Vec<String> = Vec::new();
+        for target in targets {
+            let name = self.binding_name(target, scope);
+            let unassigned = match self.bindings.get(&name) {
+                Some(IRArg::Link(slot,_)) => *self.nodes.get(*slot as usize) == IRNode::PlaceHolder,
+                _ => match self.arrays.get(target.name) {
+                    Some(len) => return Err(Diagnostic::new(format!("'{}' is out of range, array '{}' has length {}.",name,target.name,len),target.span)),
+                    None => return Err(Diagnostic::new(format!("'{}' is not an array. Arrays are declared like `let {}[8];`.",target.name,target.name),target.span))
+                }
+            };
+            if !unassigned || names.contains(&name) {
+                return Err(Diagnostic::new(format!("'{}' already has a value, array elements can only be assigned once.",name),target.span));
+            }
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    /// Checks that every element of the arrays declared with `let V[16];` was assigned.
+    fn check_assigned(&mut self, stmt: &Statement, scope: &Scope) -> Result<(),Diagnostic> {
+        match stmt {
+            Statement::ArrayDecl(name,size,span) => {
+                for i in 0..self.array_len(name, size, scope) {
+                    let key = format!("{}[{}]",name,i);
+                    if let Some(IRArg::Link(slot,_)) = self.bindings.get(&key) {
+                        if *self.nodes.get(*slot as usize) == IRNode::PlaceHolder {
+                            return Err(Diagnostic::new(format!("'{}' is declared but never assigned.",key),*span));
+                        }
                     }
                 }
+                Ok(())
             },
-            _ => ()
+            Statement::For(var,start,end,body) => {
+                self.unroll(var, start, end, scope, |ir,scope| {
+                    body.iter().try_for_each(|stmt| ir.check_assigned(stmt, scope))
+                })
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// The key a binding is stored under. Indexed bindings like `V[i]` are stored as `V[3]`.
+    fn binding_name(&self, binding: &Binding, scope: &Scope) -> String {
+        if let Some(index) = &binding.index {
+            format!("{}[{}]",binding.name,self.eval_constant(index, scope))
+        } else {
+            binding.name.to_owned()
+        }
+    }
+
+    fn eval_constant(&self, expr: &Expr, scope: &Scope) -> i64 {
+        scope.eval_constant(expr).unwrap_or_else(|err| panic!("Module '{}': {}",self.name,err))
+    }
+
+    /// Runs `f` once for every value of a compile-time loop, with the loop variable in scope.
+    fn unroll<E>(&mut self, var: &str, start: &Expr, end: &Expr, scope: &Scope,
+        mut f: impl FnMut(&mut Self,&Scope) -> Result<(),E>
+    ) -> Result<(),E> {
+        let start = self.eval_constant(start, scope);
+        let end = self.eval_constant(end, scope);
+        for i in start..end {
+            f(self,&scope.with_local(var, i))?;
         }
+        Ok(())
     }
 
-    fn add_stmt(&mut self, stmt: &Statement, module_table: &HashMap<String, IRModule>, constant_table: &HashMap<String,i64>) {
+    fn add_stmt(&mut self, stmt: &Statement, scope: &Scope) -> Result<(),Diagnostic> {
         if self.outputs_set {
             panic!("Module '{}': No statements may appear after output(...).",self.name);
         }
+        if let Some(span) = stmt.span() {
+            self.nodes.set_current_location(Some(self.location(span)));
+        }
         match stmt {
-            Statement::Output(out_exprs) => {
+            Statement::Output(out_exprs,_) => {
+                let out_args = self.add_values(out_exprs, scope);
                 if let Some(ret_types) = &self.ret_types {
-                    if out_exprs.len() != ret_types.len() {
+                    if out_args.len() != ret_types.len() {
                         panic!("The number of returned values does not match the type signature.");
                     }
                 }
-                for (out_i, expr) in out_exprs.iter().enumerate() {
-                    let out_arg = self.add_expr(expr, module_table, constant_table,  None);
-                    self.nodes.push(IRNode::Output(out_i as u32, out_arg),format!("output {}",self.name));
+                self.port_count += out_args.len() as i32;
+                for (out_i, out_arg) in out_args.into_iter().enumerate() {
+                    self.nodes.push(IRNode::Output(out_i as u32, out_arg),format!("output {} of {}",out_i,self.name));
                 }
-                self.port_count += out_exprs.len() as i32;
                 self.outputs_set = true;
             },
-            Statement::VarBinding(idents,expr) => {
-                let out_slots: Vec<_> = idents.iter().map(|ident| {
-                    if let IRArg::Link(out_slot,_) = self.bindings.get(*ident).unwrap() {
-                        *out_slot
-                    } else {
-                        panic!("var bindings should always be links");
-                    }
-                }).collect();
-
-                if out_slots.len() == 1 {
-                    self.add_expr(expr, module_table, constant_table, Some(out_slots[0]));
-                } else {
-                    if let Expr::SubModule(name,args) = expr {
-                        self.add_submodule(module_table, constant_table, name,args,Some(&out_slots));
-                    } else {
-                        panic!("multi-assignment can only be used with sub-modules");
-                    }
-                }
+            Statement::VarBinding(idents,expr,_) => {
+                let names = self.binding_names(idents, scope);
+                self.add_bound_values(&names, expr, scope);
+            },
+            Statement::Assign(targets,expr,span) => {
+                let names = self.assignment_names(targets, scope)?;
+                self.check_value_count(names.len(), expr, *span, scope)?;
+                self.add_bound_values(&names, expr, scope);
             },
+            Statement::For(var,start,end,body) => {
+                return self.unroll(var, start, end, scope, |ir,scope| {
+                    body.iter().try_for_each(|stmt| {
+                        match stmt {
+                            Statement::Output(_,span) => Err(Diagnostic::new("output(...) can not be used inside a for loop.".to_owned(),*span)),
+                            Statement::Const(_,_,span) => Err(Diagnostic::new("Constants can not be declared inside a for loop.".to_owned(),*span)),
+                            _ => ir.add_stmt(stmt, scope)
+                        }
+                    })
+                });
+            },
+            // Arrays are declared by add_stmt_bindings, and constants are evaluated before anything else in the module.
+            Statement::ArrayDecl(..) |
+            Statement::Const(..) => (),
             _ => panic!("todo handle stmt {:?}",stmt)
         }
+        Ok(())
+    }
+
+    /// Stores the values of an expression in the placeholders of the given bindings.
+    fn add_bound_values(&mut self, names: &[String], expr: &Expr, scope: &Scope) {
+        let out_slots: Vec<_> = names.iter().map(|name| {
+            if let IRArg::Link(out_slot,_) = self.bindings.get(name).unwrap() {
+                *out_slot
+            } else {
+                panic!("var bindings should always be links");
+            }
+        }).collect();
+
+        if out_slots.len() == 1 {
+            self.add_expr(expr, scope, Some(out_slots[0]));
+        } else {
+            if let Expr::SubModule(name,params,args) = expr {
+                self.add_submodule(scope, name,params,args,Some(&out_slots));
+            } else {
+                panic!("multi-assignment can only be used with sub-modules");
+            }
+        }
+
+        // Name the bound nodes after their bindings, so errors can point back to the source.
+        for (name,slot) in names.iter().zip(out_slots) {
+            self.nodes.set_binding(slot as usize, name.clone());
+        }
+    }
+
+    /// Looks up a binding. Returns None if there is no binding with the name.
+    fn get_binding(&mut self, name: &str, expr_string: String, desired_slot: Option<u32>) -> Option<IRArg> {
+        let arg = self.bindings.get(name)?.clone();
+        if desired_slot.is_some() {
+            // hack to make assignments work properly
+            return Some(self.add_node(IRNode::MultiDriver(vec!(arg)),expr_string,desired_slot));
+        }
+        Some(arg)
     }
 
     fn add_node(&mut self, node: IRNode, name: String, slot: Option<u32>) -> IRArg {
         if let Some(slot) = slot {
             assert_eq!(*self.nodes.get(slot as usize),IRNode::PlaceHolder);
             self.nodes.set(slot as usize, node, name);
+            // Array elements are declared in one statement and assigned in another.
+            self.nodes.set_location(slot as usize, self.nodes.current_location);
             IRArg::Link(slot, WireColor::None)
         } else {
             self.nodes.push(node,name);
@@ -254,35 +613,86 @@ impl IRModule {
         }
     }
 
-    fn add_expr(&mut self, expr: &Expr, module_table: &HashMap<String, IRModule>, constant_table: &HashMap<String,i64>, desired_slot: Option<u32>) -> IRArg {
+    /// IIRC the point of this is to add nodes close to a specific index, to prevent spaghetti
+    fn add_node_at(&mut self, i: usize, node: IRNode, name: String) -> IRArg {
+        let arg = self.add_node_near(i, node, name);
+        if let IRArg::Link(slot,_) = arg {
+            // The new node does the work of the one it was added for.
+            self.nodes.set_location(slot as usize, self.nodes.location(i));
+        }
+        arg
+    }
+
+    fn add_node_near(&mut self, i: usize, node: IRNode, name: String) -> IRArg {
+        let mut offset = 0;
+        while offset >= i && i + offset < self.nodes.len() {
+            if let Some(IRNode::Removed) = self.nodes.try_get(i + offset) {
+                self.nodes.set(i+offset, node, name);
+                return IRArg::Link((i + offset) as u32,WireColor::None);
+            }
+            if offset >= i {
+                if let Some(IRNode::Removed) = self.nodes.try_get(i - offset) {
+                    self.nodes.set(i-offset, node, name);
+                    return IRArg::Link((i - offset) as u32,WireColor::None);
+                }
+            }
+            offset += 1;
+        }
+        self.add_node(node,name,None)
+    }
+
+    fn add_expr(&mut self, expr: &Expr, scope: &Scope, desired_slot: Option<u32>) -> IRArg {
         let expr_string = format!("{:?}",expr);
         match expr {
             Expr::Ident(name) => {
-                if let Some(arg) = self.bindings.get(*name) {
-                    if desired_slot.is_some() {
-                        // hack to make assignments work properly
-                        let arg = arg.clone();
-                        return self.add_node(IRNode::MultiDriver(vec!(arg)),expr_string,desired_slot);
-                    }
-                    arg.clone()
-                } else if let Some(num) = constant_table.get(*name) {
-                    return self.add_node(IRNode::Constant(narrow_constant(*num)), expr_string, desired_slot);
+                if let Some(arg) = self.get_binding(name, expr_string.clone(), desired_slot) {
+                    arg
+                } else if let Some(num) = scope.find_constant(name).unwrap_or_else(|err| panic!("Module '{}': {}",self.name,err)) {
+                    self.add_node(IRNode::Constant(narrow_constant(num)), expr_string, desired_slot)
+                } else if self.arrays.contains_key(*name) {
+                    panic!("Module '{}': '{}' is an array, it can only be used as a whole when passed to a submodule or output.",self.name,name);
                 } else {
                     panic!("Module '{}': '{}' is not defined.",self.name,name);
                 }
             },
+            Expr::Path(path) => {
+                let num = scope.find_path_constant(path)
+                    .unwrap_or_else(|err| panic!("Module '{}': {}",self.name,err));
+                self.add_node(IRNode::Constant(narrow_constant(num)), expr_string, desired_slot)
+            },
+            Expr::Index(name,index) => {
+                if let Ok(index) = scope.eval_constant(index) {
+                    let key = format!("{}[{}]",name,index);
+                    return self.get_binding(&key, expr_string, desired_slot)
+                        .unwrap_or_else(|| panic!("Module '{}': '{}' is not defined.",self.name,key));
+                }
+
+                // Dynamic indexing is built just like a match over every index.
+                let len = *self.arrays.get(*name)
+                    .unwrap_or_else(|| panic!("Module '{}': '{}' is not an array.",self.name,name));
+                let arg_in = self.add_expr(index, scope, None);
+
+                let mut results = Vec::new();
+                for i in 0..len {
+                    let arg_res = self.get_element(name, i);
+                    let compare = self.add_node(IRNode::BinOp(arg_in.clone(),BinOp::CmpEq,IRArg::Constant(i as i32)),"index-cmp".to_owned(),None);
+                    results.push(self.add_node(IRNode::Gate(compare,true,arg_res),"index-gate".to_owned(),None));
+                }
+
+                self.add_node(IRNode::MultiDriver(results),"index-gather".to_owned(),desired_slot)
+            },
             Expr::Constant(num) => {
                 let num_32 = narrow_constant(*num);
                 self.add_node(IRNode::Constant(num_32), expr_string, desired_slot)
             },
             Expr::BinOp(lhs,op,rhs) => {
-                let lex = self.add_expr(lhs, module_table, constant_table,  None);
-                let rex = self.add_expr(rhs, module_table, constant_table,  None);
+                let lex = self.add_expr(lhs, scope, None);
+                let rex = self.add_expr(rhs, scope, None);
                 
                 self.add_node(IRNode::BinOp(lex,*op,rex), expr_string, desired_slot)
             },
             Expr::UnOp(op,arg) => {
-                let ir_arg = self.add_expr(arg, module_table, constant_table,  None);
+                let ir_arg = self.add_expr(arg, scope, None);
 
                 match &op {
                     UnaryOp::Negate => self.add_node(IRNode::BinOp(IRArg::Constant(0),BinOp::Sub,ir_arg),expr_string, desired_slot),
@@ -293,35 +703,29 @@ impl IRModule {
             },
             Expr::If(cond,val_true,val_false) => {
 
-                let arg_cond = self.add_expr(cond, module_table, constant_table,  None);
-                let arg_true = self.add_expr(val_true, module_table, constant_table,  None);
+                let arg_cond = self.add_expr(cond, scope, None);
+                let arg_true = self.add_expr(val_true, scope, None);
 
                 if let Some(val_false) = val_false {
-                    let arg_false = self.add_expr(val_false, module_table, constant_table,  None);
+                    let arg_false = self.add_expr(val_false, scope, None);
                     
-                    let true_result = self.add_node(IRNode::Gate(arg_cond.clone(),true,arg_true),format!("if-t"),None);
-                    let false_result = self.add_node(IRNode::Gate(arg_cond,false,arg_false),format!("if-f"),None);
+                    let true_result = self.add_node(IRNode::Gate(arg_cond.clone(),true,arg_true),"if-t".to_owned(),None);
+                    let false_result = self.add_node(IRNode::Gate(arg_cond,false,arg_false),"if-f".to_owned(),None);
                     
-                    self.add_node(IRNode::MultiDriver(vec!(true_result,false_result)),format!("if-merge"),desired_slot)
+                    self.add_node(IRNode::MultiDriver(vec!(true_result,false_result)),"if-merge".to_owned(),desired_slot)
                 } else {
-                    self.add_node(IRNode::Gate(arg_cond,true,arg_true),format!("if-single?"),desired_slot)
+                    self.add_node(IRNode::Gate(arg_cond,true,arg_true),"if-single?".to_owned(),desired_slot)
                 }
             },
             Expr::Match(expr_in,match_list) => {
-                let arg_in = self.add_expr(expr_in, module_table, constant_table, None);
+                let arg_in = self.add_expr(expr_in, scope, None);
 
                 let mut results = Vec::new();
-                for (cmp_op,expr_test,expr_res) in match_list {
-                    let arg_test = self.add_expr(expr_test, module_table, constant_table, None);
-                    let arg_res = self.add_expr(expr_res, module_table, constant_table, None);
+                self.add_match_arms(&arg_in, match_list, scope, &mut results);
 
-                    let compare = self.add_node(IRNode::BinOp(arg_in.clone(),cmp_op.clone(),arg_test),format!("match-cmp"),None);
-                    results.push(self.add_node(IRNode::Gate(compare,true,arg_res),format!("match-gate"),None));
-                }
-
-                self.add_node(IRNode::MultiDriver(results),format!("match-gather"),desired_slot)
+                self.add_node(IRNode::MultiDriver(results),"match-gather".to_owned(),desired_slot)
             },
-            Expr::SubModule(name,args) => {
+            Expr::SubModule(name,params,args) => {
                 // This is such a turd, TODO re-evaluate the way add_submodule works
                 let mut out_slots = Vec::new();
                 let mut out_slots_ref = None;
@@ -330,11 +734,11 @@ impl IRModule {
                     out_slots_ref = Some(&out_slots);
                 }
 
-                let res = self.add_submodule(module_table, constant_table, name, args, out_slots_ref);
+                let res = self.add_submodule(scope, name, params, args, out_slots_ref);
                 res.unwrap_or_else(|| IRArg::Link(desired_slot.unwrap(),WireColor::None))
                 
                 // should never be none
-                /*let args: Vec<_> = args.iter().map(|arg| self.add_expr(arg, module_table, constant_table,  None)).collect();
+                /*let args: Vec<_> = args.iter().map(|arg| self.add_expr(arg, scope, None)).collect();
                 if let Some(submod) = module_table.get(name) {
                     let offset = self.nodes.len() as u32;
                     let mut results: Vec<Option<IRArg>> = Vec::new();
@@ -362,42 +766,102 @@ impl IRModule {
         }
     }
 
-    fn add_submodule(&mut self, module_table: &HashMap<String, IRModule>, constant_table: &HashMap<String, i64>,
-        mod_name: &str, inputs: &Vec<Expr>, out_slots: Option<&Vec<u32>>
-    ) -> Option<IRArg> {
+    fn get_element(&self, name: &str, index: u32) -> IRArg {
+        let key = format!("{}[{}]",name,index);
+        self.bindings.get(&key).cloned()
+            .unwrap_or_else(|| panic!("Module '{}': '{}' is not defined.",self.name,key))
+    }
 
-        let args: Vec<_> = inputs.iter().map(|arg| self.add_expr(arg, module_table, constant_table, None)).collect();
-        if let Some(submod) = module_table.get(mod_name) {
-            let offset = self.nodes.len() as u32;
-            let mut results: Vec<Option<IRArg>> = Vec::new();
-            for (node,debug_name) in submod.nodes.iter_debug() {
-                if let Some((out_i,out_arg)) = self.add_node_from_submodule(node,debug_name, offset, &args) {
-                    let out_i = out_i as usize;
-                    if out_i >= results.len() {
-                        results.resize(out_i + 1, None);
+    /// Adds a list of values, such as submodule arguments or outputs. Arrays are expanded into their elements.
+    fn add_values(&mut self, exprs: &[Expr], scope: &Scope) -> Vec<IRArg> {
+        let mut values = Vec::new();
+        for expr in exprs {
+            if let Expr::Ident(name) = expr {
+                if !self.bindings.contains_key(*name) {
+                    if let Some(len) = self.arrays.get(*name).copied() {
+                        values.extend((0..len).map(|i| self.get_element(name, i)));
+                        continue;
                     }
-                    results[out_i] = Some(out_arg);
                 }
             }
+            values.push(self.add_expr(expr, scope, None));
+        }
+        values
+    }
+
+    fn find_submodule<'t>(&self, scope: &Scope<'t,'_>, mod_path: &ItemPath, params: &[Expr]) -> ModuleRef<'t> {
+        scope.get_module(mod_path,params)
+            .unwrap_or_else(|err| panic!("Module '{}': {}",self.name,err))
+    }
 
-            if results.len() != out_slots.map(|x| x.len()).unwrap_or(1) {
-                panic!("Submodule mismatch in expected and actual result lengths.");
+    fn output_count(&self) -> usize {
+        self.nodes.iter().filter(|node| matches!(node, IRNode::Output(..))).count()
+    }
+
+    fn add_match_arms(&mut self, arg_in: &IRArg, match_list: &[MatchArm], scope: &Scope, results: &mut Vec<IRArg>) {
+        for arm in match_list {
+            match arm {
+                MatchArm::Case(cmp_op,expr_test,expr_res) => {
+                    let arg_test = self.add_expr(expr_test, scope, None);
+                    let arg_res = self.add_expr(expr_res, scope, None);
+
+                    let compare = self.add_node(IRNode::BinOp(arg_in.clone(),*cmp_op,arg_test),"match-cmp".to_owned(),None);
+                    results.push(self.add_node(IRNode::Gate(compare,true,arg_res),"match-gate".to_owned(),None));
+                },
+                MatchArm::For(var,start,end,arms) => {
+                    let unrolled: Result<(),Infallible> = self.unroll(var, start, end, scope, |ir,scope| {
+                        ir.add_match_arms(arg_in, arms, scope, results);
+                        Ok(())
+                    });
+                    let Ok(()) = unrolled;
+                }
             }
+        }
+    }
+
+    fn add_submodule(&mut self, scope: &Scope,
+        mod_path: &ItemPath, params: &[Expr], inputs: &[Expr], out_slots: Option<&Vec<u32>>
+    ) -> Option<IRArg> {
+
+        let args = self.add_values(inputs, scope);
+        let submod = self.find_submodule(scope, mod_path, params);
+        let input_count = submod.nodes.iter().filter(|node| matches!(node, IRNode::Input(..))).count();
+        if args.len() != input_count {
+            panic!("Module '{}': Submodule '{}' takes {} inputs, but {} were given.",self.name,mod_path,input_count,args.len());
+        }
+
+        let instance_count = self.instance_counts.entry(submod.name.clone()).or_insert(0);
+        let instance = format!("{}#{}",submod.name,instance_count);
+        *instance_count += 1;
 
-            if let Some(out_slots) = out_slots {
-                for (res,out_slot) in results.iter().zip(out_slots.iter()) {
-                    self.add_node(IRNode::MultiDriver(vec!(res.clone().unwrap())), format!("submod result copy"), Some(*out_slot));
+        let offset = self.nodes.len() as u32;
+        let mut results: Vec<Option<IRArg>> = Vec::new();
+        for (i,(node,name)) in submod.nodes.iter_named().enumerate() {
+            let location = submod.nodes.location(i);
+            if let Some((out_i,out_arg)) = self.add_node_from_submodule(node,&instance,name,location, offset, &args) {
+                let out_i = out_i as usize;
+                if out_i >= results.len() {
+                    results.resize(out_i + 1, None);
                 }
-                None
-            } else {
-                Some(results[0].clone().unwrap())
+                results[out_i] = Some(out_arg);
+            }
+        }
+
+        if results.len() != out_slots.map(|x| x.len()).unwrap_or(1) {
+            panic!("Submodule mismatch in expected and actual result lengths.");
+        }
+
+        if let Some(out_slots) = out_slots {
+            for (res,out_slot) in results.iter().zip(out_slots.iter()) {
+                self.add_node(IRNode::MultiDriver(vec!(res.clone().unwrap())), "submod result copy".to_owned(), Some(*out_slot));
             }
+            None
         } else {
-            panic!("Module '{}': Submodule '{}' is not defined.",self.name,mod_name);
+            Some(results[0].clone().unwrap())
         }
     }
 
-    fn add_node_from_submodule(&mut self, node: &IRNode, old_name: &str, offset: u32, inputs: &Vec<IRArg>) -> Option<(u32,IRArg)> {
+    fn add_node_from_submodule(&mut self, node: &IRNode, instance: &str, old_name: &NodeName, location: Option<Location>, offset: u32, inputs: &[IRArg]) -> Option<(u32,IRArg)> {
         
         let offset_arg = |arg: &IRArg| {
             if let IRArg::Link(n,c) = arg {
@@ -415,7 +879,7 @@ impl IRModule {
             },
             IRNode::Output(n,arg) => {
                 // we must push a dummy node to keep indexes consistent
-                self.nodes.push(IRNode::Removed, format!("removed submodule output"));
+                self.nodes.push_at(IRNode::Removed, NodeName::new("removed submodule output".to_owned()), location);
                 return Some((*n,offset_arg(arg)));
             },
             IRNode::BinOp(lhs,op,rhs) => {
@@ -435,7 +899,9 @@ impl IRModule {
             IRNode::Removed => IRNode::Removed,
             _ => panic!("submodule node {:?}",node)
         };
-        self.nodes.push(adjusted, format!("[submod] {}",old_name));
+        let mut name = old_name.clone();
+        name.instances.insert(0,instance.to_owned());
+        self.nodes.push_at(adjusted, name, location);
         None
     }
 
@@ -458,73 +924,283 @@ impl IRModule {
     }*/
 }
 
+fn port_color(color: Option<PortColor>) -> WireColor {
+    match color {
+        Some(PortColor::Green) => WireColor::Green,
+        Some(PortColor::Both) => WireColor::Both,
+        Some(PortColor::Red) | None => WireColor::Red
+    }
+}
+
 /// We narrow from 64 bit constants so we can use both signed and unsigned 32 bit constants.
 fn narrow_constant(x: i64) -> i32 {
+    checked_narrow_constant(x).unwrap_or_else(|| panic!("constant too wide: {}",x))
+}
+
+fn checked_narrow_constant(x: i64) -> Option<i32> {
     if let Ok(n) = x.try_into() {
-        n
+        Some(n)
     } else if let Ok(n) = x.try_into() {
         let n: u32 = n;
-        n as i32
+        Some(n as i32)
     } else {
-        panic!("constant too wide: {}",x)
+        None
     }
 }
 
-// Consumes a list of AST modules and returns the IR for the final module.
+/// Builds and optimizes a single module.
+fn build_module(p_mod: &Module, name: String, settings: Rc<CompileSettings>, scope: &Scope) -> Result<IRModule,Diagnostic> {
+    let mut ir = IRModule::new(name, settings);
+    ir.file = scope.namespace.file;
+    ir.public = p_mod.public;
+
+    if p_mod.arg_types.len() != p_mod.arg_names.len() {
+        panic!("The number of args does not match the number of types. This should never happen.");
+    }
+
+    // Module constants can be used anywhere in the body, including array sizes.
+    let mut scope = scope.child();
+    for stmt in p_mod.stmts.iter() {
+        if let Statement::Const(const_name,expr,span) = stmt {
+            let num = scope.eval_constant(expr).map_err(|err| {
+                Diagnostic::new(format!("Constant '{}': {}",const_name,err),*span)
+            })?;
+            if scope.locals.insert((*const_name).to_owned(), num).is_some() {
+                return Err(Diagnostic::new(format!("Duplicate constant definition for '{}'.",const_name),*span));
+            }
+        }
+    }
+    let scope = &scope;
+
+    ir.add_args(p_mod, scope);
+    ir.add_ret_types(p_mod, scope);
+
+    for stmt in p_mod.stmts.iter() {
+        ir.add_stmt_bindings(stmt, scope)?;
+    }
+
+    for stmt in p_mod.stmts.iter() {
+        ir.add_stmt(stmt, scope)?;
+    }
+    ir.nodes.set_current_location(None);
+
+    for stmt in p_mod.stmts.iter() {
+        ir.check_assigned(stmt, scope)?;
+    }
+
+    //ir.check_multi_driver();
+
+    if ir.settings.fold_constants {
+        ir.fold_constants();
+    }
+
+    if ir.settings.prune {
+        ir.prune();
+    }
+
+    ir.fix_nodes();
+
+    // Prune again, gate expansion can leave behind orphan comparators.
+    if ir.settings.prune {
+        ir.prune();
+    }
+
+    Ok(ir)
+}
+
+// Consumes a list of AST modules and adds their IR to the module table.
+// Modules with compile-time parameters are stored as templates, and built when they are used.
 // Runs checks on the modules. May panic if an error is encountered.
-pub fn build_ir(
-    parse_mods: Vec<ParseItem>,
+// Errors in constant expressions are returned, so they can be shown with the offending source.
+// Test blocks are stored in the module table, to be run later.
+pub fn build_ir<'a>(
+    parse_mods: Vec<ParseItem<'a>>,
     settings: Rc<CompileSettings>,
-    modules: &mut HashMap<String,IRModule>,
-    constants: &mut HashMap<String,i64>
-) {
+    namespace: &Namespace,
+    modules: &mut ModuleTable<'a>
+) -> Result<(),Diagnostic> {
+    // Tests without a module name are for the module declared just before them.
+    let mut last_module: Option<&'a str> = None;
     for p_item in parse_mods {
         match p_item {
-            ParseItem::Constant(name,num) => {
-                if constants.insert(name.to_owned(), num).is_some() {
-                    panic!("Duplicate constant definition for '{}'.",name);
+            ParseItem::Constant(name,expr,span) => {
+                let scope = Scope{modules, namespace, locals: HashMap::new()};
+                let num = scope.eval_constant(&expr).map_err(|err| {
+                    Diagnostic::new(format!("Constant '{}': {}",name,err),span)
+                })?;
+
+                modules.add_constant(namespace.file, name.to_owned(), num)
+                    .map_err(|err| Diagnostic::new(err,span))?;
+            },
+            ParseItem::Enum(p_enum) => {
+                let mut values: Vec<(&str,i64)> = Vec::new();
+                let mut next = 0;
+                for &(member,ref expr,span) in &p_enum.members {
+                    let num = if let Some(expr) = expr {
+                        // Earlier members can be referred to without the enum's name.
+                        let locals = values.iter().map(|(name,num)| ((*name).to_owned(),*num)).collect();
+                        let scope = Scope{modules, namespace, locals};
+                        scope.eval_constant(expr).map_err(|err| {
+                            Diagnostic::new(format!("Enum member '{}::{}': {}",p_enum.name,member,err),span)
+                        })?
+                    } else {
+                        next
+                    };
+
+                    if let Some((other,_)) = values.iter().find(|(_,other_num)| *other_num == num) {
+                        return Err(Diagnostic::new(format!("Enum member '{}::{}' has the same value as '{}::{}' ({}).",
+                            p_enum.name,member,p_enum.name,other,num),span).with_label("duplicate value".to_owned()));
+                    }
+                    values.push((member,num));
+                    next = num + 1;
+
+                    modules.add_constant(namespace.file, format!("{}::{}",p_enum.name,member), num)
+                        .map_err(|err| Diagnostic::new(err,span))?;
                 }
             },
+            // Imports are resolved by the loader before IR is built.
+            ParseItem::Use(..) => (),
+            ParseItem::Test(mut test) => {
+                let (module,params) = match (test.module.take(),last_module) {
+                    (Some(module),_) => module,
+                    (None,Some(name)) => (ItemPath{namespace: None, name},Vec::new()),
+                    (None,None) => return Err(Diagnostic::new(
+                        format!("Test '{}' does not follow a module. Use `test {} for module {{ ... }}` to name the module it tests.",test.name,test.name),
+                        test.span))
+                };
+                modules.add_test(testbench::TestCase{test,namespace: namespace.clone(),module,params});
+            },
             ParseItem::Module(p_mod) => {
-                let mut ir = IRModule::new(p_mod.name.to_owned(), settings.clone());
-                ir.arg_types = p_mod.arg_types;
-                ir.ret_types = p_mod.ret_types;
-        
-                if ir.arg_types.len() != p_mod.arg_names.len() {
-                    panic!("The number of args does not match the number of types. This should never happen.");
-                }
-        
-                ir.add_args(&p_mod.arg_names);
-        
-                for stmt in p_mod.stmts.iter() {
-                    ir.add_stmt_bindings(&stmt);
-                }
-        
-                for stmt in p_mod.stmts {
-                    ir.add_stmt(&stmt, modules, constants);
-                }
-        
-                //ir.check_multi_driver();
-        
-                if ir.settings.fold_constants {
-                    ir.fold_constants();
-                }
-        
-                if ir.settings.prune {
-                    ir.prune();
-                }
-        
-                ir.fix_nodes();
-        
-                // Prune again, gate expansion can leave behind orphan comparators.
-                if ir.settings.prune {
-                    ir.prune();
-                }
-                
-                if modules.insert(ir.name.clone(), ir).is_some() {
-                    panic!("Duplicate module definition for '{}'.",p_mod.name);
+                last_module = Some(p_mod.name);
+                let name_span = p_mod.name_span;
+                if !p_mod.params.is_empty() {
+                    modules.add_template(namespace.file, p_mod, namespace, settings.clone())
+                        .map_err(|err| Diagnostic::new(err,name_span))?;
+                    continue;
                 }
+
+                let scope = Scope{modules, namespace, locals: HashMap::new()};
+                let ir = build_module(&p_mod, p_mod.name.to_owned(), settings.clone(), &scope)?;
+                modules.add(namespace.file, ir)
+                    .map_err(|err| Diagnostic::new(err,name_span))?;
             },
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a module and runs it with the given inputs for enough ticks that its outputs settle.
+    fn run(source: &str, inputs: &[i32]) -> Vec<i32> {
+        let symbols = test_util::symbols();
+        let module = test_util::build(&symbols,source,"main").unwrap();
+        let mut sim = Simulator::new(&module);
+        for (port,value) in inputs.iter().enumerate() {
+            sim.set_input(port,*value);
+        }
+        for _ in 0..10 {
+            sim.step();
+        }
+        (0..sim.output_count()).map(|port| sim.get_output(port)).collect()
+    }
+
+    #[test]
+    fn local_constant_errors() {
+        let symbols = test_util::symbols();
+        let err = test_util::build(&symbols,"mod main(x) {\n    const K = 1 / 0;\n    output(x + K);\n}","main").unwrap_err();
+        assert_eq!(err,"Constant 'K': Division by zero: 1 / 0");
+        let err = test_util::build(&symbols,"mod main(x) {\n    const K = 1;\n    const K = 2;\n    output(x + K);\n}","main").unwrap_err();
+        assert_eq!(err,"Duplicate constant definition for 'K'.");
+    }
+
+    const SHIFTER: &str = "
+mod shifter<WIDTH, OFFSET>(x) {
+    output((x << OFFSET) & ((1 << WIDTH) - 1));
+}
+";
+
+    #[test]
+    fn templates_are_specialized() {
+        let source = format!("{}
+mod main(x) {{
+    output(shifter::<4,1>(x), shifter::<8,0>(x), shifter::<8,1>(x + 1));
+}}",SHIFTER);
+        assert_eq!(run(&source,&[0x1F]),[0xE,0x1F,0x40]);
+
+        // The parameters are constants in each instance, so expressions of them are folded.
+        let symbols = test_util::symbols();
+        let module = test_util::build(&symbols,&source,"main").unwrap();
+        for node in module.nodes.iter() {
+            assert!(!matches!(node,IRNode::BinOp(IRArg::Constant(_),_,IRArg::Constant(_))),"{:?}",node);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Module 'main': Submodule 'shifter' expects 2 compile-time parameter(s), but 1 were given.")]
+    fn template_parameter_count() {
+        let symbols = test_util::symbols();
+        let source = format!("{}\nmod main(x) {{ output(shifter::<4>(x)); }}",SHIFTER);
+        test_util::build(&symbols,&source,"main").ok();
+    }
+
+    #[test]
+    fn for_loops_unroll() {
+        let source = "
+mod main(x) {
+    let v[4];
+    for i in 0..4 {
+        v[i] = x * (i + 1);
+    }
+    output(v[0] + v[3], match(x) { for i in 0..4 { i => v[i] }, 4 => 100 });
+}";
+        assert_eq!(run(source,&[2]),[10,6]);
+        assert_eq!(run(source,&[4]),[20,100]);
+        assert_eq!(run(source,&[9]),[45,0]);
+    }
+
+    #[test]
+    fn nested_for_loops() {
+        let source = "
+mod main(x) {
+    let v[6];
+    for i in 0..2 {
+        for j in 0..3 {
+            v[i * 3 + j] = x + i * 10 + j;
+        }
+    }
+    output(v[0], v[2], v[4], v[5]);
+}";
+        assert_eq!(run(source,&[100]),[100,102,111,112]);
+    }
+
+    #[test]
+    fn arrays() {
+        let source = "
+mod pair(a) {
+    output(a, a + 1);
+}
+mod main(x, i) {
+    let t[3];
+    t[0] = x;
+    (t[1], t[2]) = pair(x * 10);
+    let r[2] = pair(x);
+    output(t[i], r[1]);
+}";
+        assert_eq!(run(source,&[3,0]),[3,4]);
+        assert_eq!(run(source,&[3,2]),[31,4]);
+        // Indexes past the end of the array read nothing.
+        assert_eq!(run(source,&[3,5]),[0,4]);
+    }
+
+    #[test]
+    fn array_errors() {
+        let symbols = test_util::symbols();
+        let err = test_util::build(&symbols,"mod main(x) {\n    let t[2];\n    t[0] = x;\n    t[0] = x;\n    output(t[0]);\n}","main").unwrap_err();
+        assert_eq!(err,"'t[0]' already has a value, array elements can only be assigned once.");
+        let err = test_util::build(&symbols,"mod main(x) {\n    let t[2];\n    t[0] = x;\n    output(t[0]);\n}","main").unwrap_err();
+        assert_eq!(err,"'t[1]' is declared but never assigned.");
+    }
 }
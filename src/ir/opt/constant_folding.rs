@@ -55,7 +55,7 @@ impl IRModule {
                             continue;
                         }
     
-                        self.nodes.update(index,IRNode::BinOp(lhs,op.clone(),rhs));
+                        self.nodes.update(index,IRNode::BinOp(lhs,op,rhs));
                     },
                     IRNode::Gate(cond,check,gated) => {
                         let cond = self.fix_const(&cond);
@@ -99,7 +99,7 @@ impl IRModule {
                         });
 
                         if const_sum != 0 || filtered_args.len() != args.len() {
-                            if filtered_args.len() == 0 {
+                            if filtered_args.is_empty() {
                                 self.nodes.update(index, IRNode::Constant(const_sum));
                             } else {
                                 if const_sum != 0 {
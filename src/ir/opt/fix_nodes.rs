@@ -1,7 +1,7 @@
 // This is not really an optimization pass, but it happens during optimization.
 // It expands Gates and makes sure some requirements for conversion to combinators are satasfied.
 
-use crate::{common::BinOp, ir::WireColor};
+use crate::common::BinOp;
 
 use super::super::{IRModule, IRNode, IRArg};
 
@@ -13,7 +13,7 @@ impl IRModule {
                 IRNode::BinOp(lhs,op,rhs) => {
                     if lhs.is_link() && lhs == rhs {
                         // 1. fix same-arg binops
-                        self.nodes.update(i, IRNode::BinOpSame(lhs.clone(),op.clone()));
+                        self.nodes.update(i, IRNode::BinOpSame(lhs.clone(),op));
                     } else if op.is_compare() {
                         // 2. fix comparisons (lhs cannot be constant)
                         if lhs.is_link() {
@@ -21,7 +21,6 @@ impl IRModule {
                         } else if rhs.is_link() {
                             self.nodes.update(i,IRNode::BinOp(rhs.clone(),op.flip(),lhs.clone()));
                         } else if let IRArg::Constant(n) = lhs {
-                            let op = op.clone();
                             let rhs = rhs.clone();
 
                             let lhs_const = self.add_node_at(i,IRNode::Constant(n),format!("const {}",n));
@@ -46,52 +45,49 @@ impl IRModule {
         // 4. expand gates
         for i in 0..self.nodes.len() {
             let node = self.nodes.get(i);
-            match node {
-                IRNode::Gate(cond,check,gated) => {
-                    let check = *check;
-                    let cond = cond.clone();
-                    let gated = gated.clone();
+            if let IRNode::Gate(cond,check,gated) = node {
+                let check = *check;
+                let cond = cond.clone();
+                let gated = gated.clone();
 
-                    let fixed_gated = if let IRArg::Constant(x) = gated {
-                        self.add_node_at(i, IRNode::Constant(x),format!("gate const {}",x))
-                    } else {
-                        gated
-                    };
+                let fixed_gated = if let IRArg::Constant(x) = gated {
+                    self.add_node_at(i, IRNode::Constant(x),format!("gate const {}",x))
+                } else {
+                    gated
+                };
 
-                    // If cond is a comparison, we can re-use it.
-                    if let IRArg::Link(cond_id,_) = cond {
-                        let cond_node = self.nodes.get(cond_id as usize);
-                        if let IRNode::BinOp(lhs,op,IRArg::Constant(rhs)) = cond_node {
-                            assert!(lhs.is_link()); // should always be true (stage 2)
-                            if op.is_compare() {
+                // If cond is a comparison, we can re-use it.
+                if let IRArg::Link(cond_id,_) = cond {
+                    let cond_node = self.nodes.get(cond_id as usize);
+                    if let IRNode::BinOp(lhs,op,IRArg::Constant(rhs)) = cond_node {
+                        assert!(lhs.is_link()); // should always be true (stage 2)
+                        if op.is_compare() {
 
-                                let new_node = if check {
-                                    IRNode::BinOpCmpGate(lhs.clone(), op.clone(), *rhs, fixed_gated)
-                                } else {
-                                    IRNode::BinOpCmpGate(lhs.clone(), op.invert(), *rhs, fixed_gated)
-                                };
-                                self.nodes.update(i, new_node);
+                            let new_node = if check {
+                                IRNode::BinOpCmpGate(lhs.clone(), *op, *rhs, fixed_gated)
+                            } else {
+                                IRNode::BinOpCmpGate(lhs.clone(), op.invert(), *rhs, fixed_gated)
+                            };
+                            self.nodes.update(i, new_node);
 
-                                continue;
-                            }
+                            continue;
                         }
                     }
+                }
 
-                    // LHS and gated must be signals.
-                    let fixed_cond = if let IRArg::Constant(x) = cond {
-                        self.add_node_at(i, IRNode::Constant(x),format!("gate const {}",x))
-                    } else {
-                        cond
-                    };
+                // LHS and gated must be signals.
+                let fixed_cond = if let IRArg::Constant(x) = cond {
+                    self.add_node_at(i, IRNode::Constant(x),format!("gate const {}",x))
+                } else {
+                    cond
+                };
 
-                    let new_node = if check {
-                        IRNode::BinOpCmpGate(fixed_cond, BinOp::CmpNeq, 0, fixed_gated)
-                    } else {
-                        IRNode::BinOpCmpGate(fixed_cond, BinOp::CmpEq, 0, fixed_gated)
-                    };
-                    self.nodes.update(i, new_node);
-                },
-                _ => ()
+                let new_node = if check {
+                    IRNode::BinOpCmpGate(fixed_cond, BinOp::CmpNeq, 0, fixed_gated)
+                } else {
+                    IRNode::BinOpCmpGate(fixed_cond, BinOp::CmpEq, 0, fixed_gated)
+                };
+                self.nodes.update(i, new_node);
             }
         }
 
@@ -127,23 +123,4 @@ impl IRModule {
         }
         false
     }
-
-    /// IIRC the point of this is to add nodes close to a specific index, to prevent spaghetti
-    fn add_node_at(&mut self, i: usize, node: IRNode, name: String) -> IRArg {
-        let mut offset = 0;
-        while offset >= i && i + offset < self.nodes.len() {
-            if let Some(IRNode::Removed) = self.nodes.try_get(i + offset) {
-                self.nodes.set(i+offset, node, name);
-                return IRArg::Link((i + offset) as u32,WireColor::None);
-            }
-            if offset >= i {
-                if let Some(IRNode::Removed) = self.nodes.try_get(i - offset) {
-                    self.nodes.set(i-offset, node, name);
-                    return IRArg::Link((i - offset) as u32,WireColor::None);
-                }
-            }
-            offset += 1;
-        }
-        self.add_node(node,name,None)
-    }
 }
@@ -1,7 +1,7 @@
 
 use super::super::{IRModule, IRNode, IRArg};
 
-fn add_arg(arg: &IRArg, saved: &mut Vec<bool>, stack: &mut Vec<usize>) {
+fn add_arg(arg: &IRArg, saved: &mut [bool], stack: &mut Vec<usize>) {
     if let IRArg::Link(id,_) = arg {
         if !saved[*id as usize] {
             saved[*id as usize] = true;
@@ -31,8 +31,7 @@ impl IRModule {
             }
         }
 
-        while stack.len() > 0 {
-            let i = stack.pop().unwrap();
+        while let Some(i) = stack.pop() {
             let node = self.nodes.get(i);
 
             match node {
@@ -65,8 +64,8 @@ impl IRModule {
         }
 
         let mut _remove_count = 0;
-        for i in 0..self.nodes.len() {
-            if !saved[i] {
+        for (i,is_saved) in saved.into_iter().enumerate() {
+            if !is_saved {
                 self.nodes.set(i,IRNode::Removed,"pruned".to_owned());
                 _remove_count += 1;
             }
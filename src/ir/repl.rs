@@ -0,0 +1,147 @@
+// An interactive prompt for poking at a module in the simulator.
+
+use std::io::{self, BufRead, Write};
+
+use super::IRModule;
+use super::sim::Simulator;
+
+const HELP: &str = "\
+Commands:
+  set <input> <value>   Drive an input, like `set addr 5` or `set V[3] -1`.
+  step [ticks]          Run one tick, or the given number of ticks.
+  print <name>...       Print inputs, bindings, or outputs. `out` is the first output, and `out[n]` is output n.
+  watch <name>...       Print values after every tick.
+  unwatch <name>...     Stop printing values after every tick.
+  reset                 Clear every input and combinator, as if the blueprint was just placed.
+  help                  Show this message.
+  quit                  Exit.";
+
+/// Steps a module by hand. Names are looked up the same way as in tests.
+pub struct Repl<'m> {
+    module: &'m IRModule,
+    sim: Simulator<'m>,
+    /// Names printed after every tick.
+    watches: Vec<String>
+}
+
+/// Reads a value, which may be decimal or hex.
+fn parse_value(text: &str) -> Result<i32,String> {
+    let (negative,digits) = match text.strip_prefix('-') {
+        Some(digits) => (true,digits),
+        None => (false,text)
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex,16),
+        None => digits.parse::<i64>()
+    }.map_err(|_| format!("'{}' is not a number.",text))?;
+    let value = if negative { -value } else { value };
+    super::checked_narrow_constant(value).ok_or_else(|| format!("{} does not fit in 32 bits.",text))
+}
+
+impl<'m> Repl<'m> {
+    pub fn new(module: &'m IRModule) -> Self {
+        Repl{module, sim: Simulator::new(module), watches: Vec::new()}
+    }
+
+    /// Reads commands from stdin until it is closed or `quit` is entered.
+    pub fn run(&mut self) {
+        println!("Simulating module '{}'. Type `help` for a list of commands.",self.module.name);
+        let stdin = io::stdin();
+        let mut line = String::new();
+        loop {
+            print!("{}> ",self.sim.tick());
+            io::stdout().flush().ok();
+
+            line.clear();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) => {
+                    println!();
+                    break;
+                },
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("error: failed to read input: {}",err);
+                    break;
+                }
+            }
+            match self.command(&line) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(err) => println!("error: {}",err)
+            }
+        }
+    }
+
+    /// Runs a single command. Returns false when it is time to exit.
+    fn command(&mut self, line: &str) -> Result<bool,String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true)
+        };
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            "set" => {
+                if let [name,value] = args[..] {
+                    let port = self.sim.find_input(name)?;
+                    self.sim.set_input(port,parse_value(value)?);
+                } else {
+                    return Err("Usage: set <input> <value>".to_owned());
+                }
+            },
+            "step" => {
+                let ticks = match args[..] {
+                    [] => 1,
+                    [ticks] => ticks.parse::<u32>().map_err(|_| format!("'{}' is not a number of ticks.",ticks))?,
+                    _ => return Err("Usage: step [ticks]".to_owned())
+                };
+                for _ in 0..ticks {
+                    self.sim.step();
+                    self.print_watches();
+                }
+            },
+            "print" | "watch" | "unwatch" if args.is_empty() => {
+                return Err(format!("Usage: {} <name>...",command));
+            },
+            "print" => {
+                for name in args {
+                    println!("{} = {}",name,self.sim.read(name)?);
+                }
+            },
+            "watch" => {
+                for name in args {
+                    let value = self.sim.read(name)?;
+                    if !self.watches.iter().any(|other| other == name) {
+                        self.watches.push(name.to_owned());
+                    }
+                    println!("{} = {}",name,value);
+                }
+            },
+            "unwatch" => {
+                for name in args {
+                    if !self.watches.iter().any(|other| other == name) {
+                        return Err(format!("'{}' is not being watched.",name));
+                    }
+                    self.watches.retain(|other| other != name);
+                }
+            },
+            "reset" => self.sim.reset(),
+            "help" => println!("{}",HELP),
+            "quit" | "exit" => return Ok(false),
+            _ => return Err(format!("Unknown command '{}'. Type `help` for a list of commands.",command))
+        }
+        Ok(true)
+    }
+
+    fn print_watches(&self) {
+        if self.watches.is_empty() {
+            return;
+        }
+        // Watched names were checked when they were added.
+        let values: Vec<String> = self.watches.iter()
+            .map(|name| format!("{} = {}",name,self.sim.read(name).unwrap()))
+            .collect();
+        println!("tick {}: {}",self.sim.tick(),values.join(", "));
+    }
+}
@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::CompileSettings;
+use crate::common::UnaryOp;
+use crate::parser::{Expr, ItemPath, Module};
+
+use super::{IRModule, checked_narrow_constant};
+use super::testbench::TestCase;
+
+/// A module with compile-time parameters. It is built separately for each set of parameter values it is used with.
+pub(super) struct Template<'a> {
+    module: Module<'a>,
+    /// The namespace of the file the template was declared in, which its body is resolved against.
+    namespace: Namespace,
+    settings: Rc<CompileSettings>
+}
+
+/// Identifies a template instance by file, template name, and parameter values.
+type InstanceKey = (usize,String,Vec<i64>);
+
+/// Every module that has been built, keyed by the file it was declared in.
+#[derive(Default)]
+pub struct ModuleTable<'a> {
+    modules: HashMap<(usize,String),IRModule>,
+    templates: HashMap<(usize,String),Template<'a>>,
+    /// Templates that have already been built, keyed by their parameter values.
+    instances: RefCell<HashMap<InstanceKey,Rc<IRModule>>>,
+    /// Templates that are currently being built, used to detect recursion.
+    instantiating: RefCell<Vec<(usize,String)>>,
+    /// Constants and enum members, keyed by the file they were declared in.
+    constants: HashMap<(usize,String),i64>,
+    pub(super) tests: Vec<TestCase<'a>>
+}
+
+impl<'a> ModuleTable<'a> {
+    pub fn get(&self, file: usize, name: &str) -> Option<&IRModule> {
+        self.modules.get(&(file,name.to_owned()))
+    }
+
+    pub fn get_mut(&mut self, file: usize, name: &str) -> Option<&mut IRModule> {
+        self.modules.get_mut(&(file,name.to_owned()))
+    }
+
+    #[cfg(test)]
+    pub(super) fn take(&mut self, file: usize, name: &str) -> Option<IRModule> {
+        self.modules.remove(&(file,name.to_owned()))
+    }
+
+    pub fn is_template(&self, file: usize, name: &str) -> bool {
+        self.templates.contains_key(&(file,name.to_owned()))
+    }
+
+    fn check_duplicate(&self, file: usize, name: &str) -> Result<(),String> {
+        if self.get(file,name).is_some() || self.is_template(file,name) {
+            return Err(format!("Duplicate module definition for '{}'.",name));
+        }
+        Ok(())
+    }
+
+    pub(super) fn add(&mut self, file: usize, module: IRModule) -> Result<(),String> {
+        self.check_duplicate(file,&module.name)?;
+        self.modules.insert((file,module.name.clone()),module);
+        Ok(())
+    }
+
+    pub(super) fn add_template(&mut self, file: usize, module: Module<'a>, namespace: &Namespace, settings: Rc<CompileSettings>) -> Result<(),String> {
+        self.check_duplicate(file,module.name)?;
+        self.templates.insert((file,module.name.to_owned()),Template{module,namespace: namespace.clone(),settings});
+        Ok(())
+    }
+
+    pub(super) fn add_constant(&mut self, file: usize, name: String, value: i64) -> Result<(),String> {
+        if self.constants.contains_key(&(file,name.clone())) {
+            return Err(format!("Duplicate constant definition for '{}'.",name));
+        }
+        self.constants.insert((file,name),value);
+        Ok(())
+    }
+
+    fn get_constant(&self, file: usize, name: &str) -> Option<i64> {
+        self.constants.get(&(file,name.to_owned())).copied()
+    }
+
+    pub(super) fn add_test(&mut self, test: TestCase<'a>) {
+        self.tests.push(test);
+    }
+
+    fn find(&self, file: usize, name: &str) -> Option<(bool,FoundModule<'_,'a>)> {
+        if let Some(module) = self.get(file,name) {
+            Some((module.public,FoundModule::Module(module)))
+        } else {
+            self.templates.get(&(file,name.to_owned())).map(|template| {
+                (template.module.public,FoundModule::Template(file,template))
+            })
+        }
+    }
+
+    fn instantiate(&self, file: usize, template: &Template<'a>, params: Vec<i64>) -> Result<Rc<IRModule>,String> {
+        let p_mod = &template.module;
+        if params.len() != p_mod.params.len() {
+            return Err(format!("Submodule '{}' expects {} compile-time parameter(s), but {} were given.",
+                p_mod.name,p_mod.params.len(),params.len()));
+        }
+
+        let key: InstanceKey = (file,p_mod.name.to_owned(),params);
+        if let Some(instance) = self.instances.borrow().get(&key) {
+            return Ok(instance.clone());
+        }
+
+        let recursive_key = (file,p_mod.name.to_owned());
+        if self.instantiating.borrow().contains(&recursive_key) {
+            return Err(format!("Submodule '{}' instantiates itself, recursive modules are not supported.",p_mod.name));
+        }
+        self.instantiating.borrow_mut().push(recursive_key);
+
+        let params = &key.2;
+        let param_list: Vec<_> = params.iter().map(|x| x.to_string()).collect();
+        let name = format!("{}<{}>",p_mod.name,param_list.join(","));
+
+        let scope = Scope{
+            modules: self,
+            namespace: &template.namespace,
+            locals: p_mod.params.iter().map(|x| (*x).to_owned()).zip(params.iter().copied()).collect()
+        };
+        let instance = super::build_module(p_mod,name,template.settings.clone(),&scope);
+
+        self.instantiating.borrow_mut().pop();
+        // The span is in the template's file, which may not be the one being built.
+        let instance = Rc::new(instance.map_err(|err| err.message)?);
+        self.instances.borrow_mut().insert(key.clone(),instance.clone());
+        Ok(instance)
+    }
+}
+
+enum FoundModule<'t,'a> {
+    Module(&'t IRModule),
+    Template(usize,&'t Template<'a>)
+}
+
+/// A submodule that is ready to be inlined, either a plain module or an instantiated template.
+pub(super) enum ModuleRef<'t> {
+    Module(&'t IRModule),
+    Instance(Rc<IRModule>)
+}
+
+impl<'t> Deref for ModuleRef<'t> {
+    type Target = IRModule;
+
+    fn deref(&self) -> &IRModule {
+        match self {
+            ModuleRef::Module(module) => module,
+            ModuleRef::Instance(module) => module
+        }
+    }
+}
+
+/// The files visible from a single source file.
+#[derive(Clone)]
+pub struct Namespace {
+    pub file: usize,
+    /// Files imported with `use`, by alias.
+    pub imports: Vec<(String,usize)>,
+    /// The prelude is searched after all imports, and can be named explicitly as `std`.
+    pub prelude: Option<usize>
+}
+
+impl Namespace {
+    fn find_import(&self, alias: &str) -> Option<usize> {
+        self.imports.iter().find(|(name,_)| name == alias).map(|(_,file)| *file)
+            .or(if alias == "std" { self.prelude } else { None })
+    }
+}
+
+/// Everything a module body can refer to while it is being built.
+pub(super) struct Scope<'t,'a> {
+    pub modules: &'t ModuleTable<'a>,
+    pub namespace: &'t Namespace,
+    /// Compile-time values only visible to this module: parameters, module constants, and loop variables.
+    /// These shadow global constants.
+    pub locals: HashMap<String,i64>
+}
+
+impl<'t,'a> Scope<'t,'a> {
+    /// A copy of this scope, which locals can be added to without affecting the original.
+    pub fn child(&self) -> Scope<'t,'a> {
+        Scope{modules: self.modules, namespace: self.namespace, locals: self.locals.clone()}
+    }
+
+    /// A copy of this scope with one more local value, such as a loop variable.
+    pub fn with_local(&self, name: &str, value: i64) -> Scope<'t,'a> {
+        let mut scope = self.child();
+        scope.locals.insert(name.to_owned(),value);
+        scope
+    }
+
+    /// Looks up a constant or enum member. Locals are searched first, then the current file,
+    /// then imported files, then the prelude.
+    pub fn find_constant(&self, name: &str) -> Result<Option<i64>,String> {
+        if let Some(num) = self.locals.get(name) {
+            return Ok(Some(*num));
+        }
+        if let Some(num) = self.modules.get_constant(self.namespace.file,name) {
+            return Ok(Some(num));
+        }
+
+        let mut found: Option<(&str,i64)> = None;
+        for (alias,file) in &self.namespace.imports {
+            if let Some(num) = self.modules.get_constant(*file,name) {
+                if let Some((other_alias,_)) = found {
+                    return Err(format!("Constant '{}' is ambiguous, it is declared by both '{}' and '{}'. Use a qualified name like '{}::{}'.",
+                        name,other_alias,alias,alias,name));
+                }
+                found = Some((alias,num));
+            }
+        }
+        if let Some((_,num)) = found {
+            return Ok(Some(num));
+        }
+
+        Ok(self.namespace.prelude.and_then(|prelude| self.modules.get_constant(prelude,name)))
+    }
+
+    /// Looks up a qualified constant: either an enum member like `Alu::Or`, or a constant
+    /// from an imported file like `math::WIDTH`.
+    pub fn find_path_constant(&self, path: &ItemPath) -> Result<i64,String> {
+        if let Some(num) = self.find_constant(&path.to_string())? {
+            return Ok(num);
+        }
+        if let Some(file) = path.namespace.and_then(|alias| self.namespace.find_import(alias)) {
+            if let Some(num) = self.modules.get_constant(file,path.name) {
+                return Ok(num);
+            }
+        }
+        Err(format!("'{}' is not defined.",path))
+    }
+
+    /// Evaluates an expression at compile time, such as a constant or compile-time parameter.
+    /// Overflow is an error rather than wrapping like it would in a combinator.
+    pub fn eval_constant(&self, expr: &Expr) -> Result<i64,String> {
+        let narrow = |x: i64| checked_narrow_constant(x).ok_or_else(|| format!("Constant {} does not fit in 32 bits.",x));
+        match expr {
+            Expr::Constant(num) => Ok(narrow(*num)? as i64),
+            Expr::Ident(name) => self.find_constant(name)?
+                .ok_or_else(|| format!("'{}' is not a constant. Constants must be defined before they are used.",name)),
+            Expr::Path(path) => self.find_path_constant(path),
+            Expr::UnOp(op,arg) => {
+                let arg = narrow(self.eval_constant(arg)?)?;
+                let res = match op {
+                    UnaryOp::Negate => arg.checked_neg().ok_or_else(|| format!("Overflow in constant expression: -({})",arg))?,
+                    UnaryOp::Plus => arg,
+                    UnaryOp::NotBitwise => !arg,
+                    UnaryOp::NotLogical => (arg == 0) as i32
+                };
+                Ok(res as i64)
+            },
+            Expr::BinOp(lhs,op,rhs) => {
+                let lhs = narrow(self.eval_constant(lhs)?)?;
+                let rhs = narrow(self.eval_constant(rhs)?)?;
+                Ok(op.checked_fold(lhs,rhs)? as i64)
+            },
+            Expr::If(cond,val_true,Some(val_false)) => {
+                if self.eval_constant(cond)? != 0 {
+                    self.eval_constant(val_true)
+                } else {
+                    self.eval_constant(val_false)
+                }
+            },
+            _ => Err(format!("Expression can not be evaluated at compile time: {:?}",expr))
+        }
+    }
+
+    /// Resolves a submodule and instantiates it with the given compile-time parameters.
+    pub fn get_module(&self, path: &ItemPath, params: &[Expr]) -> Result<ModuleRef<'t>,String> {
+        match self.find_module(path)? {
+            FoundModule::Module(module) => {
+                if !params.is_empty() {
+                    return Err(format!("Submodule '{}' does not take compile-time parameters.",path));
+                }
+                Ok(ModuleRef::Module(module))
+            },
+            FoundModule::Template(file,template) => {
+                let params = params.iter().map(|param| self.eval_constant(param)).collect::<Result<Vec<_>,_>>()?;
+                self.modules.instantiate(file,template,params).map(ModuleRef::Instance)
+            }
+        }
+    }
+
+    /// Resolves a submodule name. Unqualified names are looked up in the current file,
+    /// then in the public modules of imported files, then in the prelude.
+    fn find_module(&self, path: &ItemPath) -> Result<FoundModule<'t,'a>,String> {
+        if let Some(alias) = path.namespace {
+            let file = self.namespace.find_import(alias)
+                .ok_or_else(|| format!("Namespace '{}' is not imported.",alias))?;
+            return match self.modules.find(file,path.name) {
+                Some((true,module)) => Ok(module),
+                Some((false,_)) => Err(format!("Submodule '{}' is private to its file.",path)),
+                None => Err(format!("Submodule '{}' is not defined.",path))
+            };
+        }
+
+        if let Some((_,module)) = self.modules.find(self.namespace.file,path.name) {
+            return Ok(module);
+        }
+
+        let mut found: Option<(&str,FoundModule)> = None;
+        for (alias,file) in &self.namespace.imports {
+            if let Some((true,module)) = self.modules.find(*file,path.name) {
+                if let Some((other_alias,_)) = found {
+                    return Err(format!("Submodule '{}' is ambiguous, it is exported by both '{}' and '{}'. Use a qualified name like '{}::{}'.",
+                        path.name,other_alias,alias,alias,path.name));
+                }
+                found = Some((alias,module));
+            }
+        }
+        if let Some((_,module)) = found {
+            return Ok(module);
+        }
+
+        if let Some(prelude) = self.namespace.prelude {
+            if let Some((true,module)) = self.modules.find(prelude,path.name) {
+                return Ok(module);
+            }
+        }
+
+        Err(format!("Submodule '{}' is not defined.",path))
+    }
+}
@@ -1,123 +1,414 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use super::{IRArg, IRModule, IRNode, WireColor};
+use crate::common::BinOp;
+use crate::loader::SourceFile;
 
+use super::{IRArg, IRModule, IRNode, WireColor};
 
-#[derive(Default,Clone)]
-struct ColorCounts {
-    red: i32,
-    green: i32
+/// A wire from one or more nodes into an input of a combinator or output.
+/// Links through multi-drivers are followed, so `sources` lists every node that actually drives the wire.
+struct ColorLink {
+    node: usize,
+    slot: usize,
+    group: usize,
+    /// The color the link must use because it connects to a module port, or None if it is free.
+    forced: WireColor
 }
 
-fn update_color_for_arg(arg: &mut IRArg, forbid_color: WireColor, out_color_counts: &mut Vec<ColorCounts>) -> WireColor {
-    match arg {
-        IRArg::Link(parent,color) => {
-            assert_eq!(*color,WireColor::None);
-
-            let counts = &mut out_color_counts[*parent as usize];
+/// Links that carry the same set of sources can share a single network.
+struct ColorGroup {
+    sources: Vec<u32>,
+    links: Vec<usize>,
+    /// Groups that must use a different color: either they feed the same combinator, or they share a source,
+    /// in which case a shared color would merge their networks.
+    neighbors: Vec<usize>,
+    color: WireColor,
+    /// Which colors this group's links ended up using.
+    /// A group normally uses one, unless it could not be colored as a whole.
+    nets: [bool;2]
+}
 
-            let red_picked = match forbid_color {
-                WireColor::Red => false,
-                WireColor::Green => true,
-                WireColor::None => counts.red >= counts.green
-            };
+fn color_index(color: WireColor) -> usize {
+    match color {
+        WireColor::Red => 0,
+        WireColor::Green => 1,
+        WireColor::Both | WireColor::None => panic!("no single color")
+    }
+}
 
-            *color = if red_picked {
-                counts.red += 1;
-                WireColor::Red
-            } else {
-                counts.green += 1;
-                WireColor::Green
-            };
-            
-            *color
-        },
-        IRArg::Constant(_) => WireColor::None
+/// The arguments of a node that are connected with wires.
+fn wired_args(node: &mut IRNode) -> Vec<&mut IRArg> {
+    match node {
+        IRNode::BinOp(lhs,_,rhs) |
+        IRNode::BinOpCmpGate(lhs,_,_,rhs) => vec!(lhs,rhs),
+        IRNode::BinOpSame(arg,_) |
+        IRNode::Output(_,arg) => vec!(arg),
+        _ => Vec::new()
     }
 }
 
 impl IRModule {
-    /// Is the argument connected to a module input?
-    fn is_input(&self, arg: &IRArg) -> bool {
-        match arg {
-            IRArg::Link(parent,_) => {
-                let node = self.nodes.get(*parent as usize);
-                if let IRNode::Input(_) = node {
-                    true
-                } else if let IRNode::MultiDriver(args) = node {
-                    args.iter().any(|x| self.is_input(x))
-                } else {
-                    false
+    /// The wire color of the module inputs an argument is connected to, or None if it isn't connected to any.
+    /// If the inputs use different colors, the first one is returned. `check_input_colors` reports those.
+    fn input_color(&self, arg: &IRArg) -> WireColor {
+        let mut sources = Vec::new();
+        self.arg_sources(arg, &mut sources);
+        sources.iter().find_map(|source| self.port_color(*source)).unwrap_or(WireColor::None)
+    }
+
+    /// Fails if any argument is connected to module inputs of different wire colors through multi-drivers,
+    /// since the inputs would then be connected to each other.
+    fn check_input_colors(&self, files: &[SourceFile]) -> Result<(),String> {
+        for i in 0..self.nodes.len() {
+            let mut node = self.nodes.get(i).clone();
+            for arg in wired_args(&mut node) {
+                let mut sources = Vec::new();
+                self.arg_sources(arg, &mut sources);
+                let mut inputs = sources.iter().filter(|source| self.port_color(**source).is_some());
+                if let Some(first) = inputs.next() {
+                    if let Some(other) = inputs.find(|other| self.port_color(**other) != self.port_color(*first)) {
+                        return Err(format!("Module '{}': {} and {} are module inputs on different wire colors, but they drive the same wire.",
+                            self.name,self.nodes.describe(*first as usize,files),self.nodes.describe(*other as usize,files)));
+                    }
                 }
-            },
-            IRArg::Constant(_) => false
+            }
         }
+        Ok(())
     }
 
-    pub fn select_colors(&mut self) {
-        let mut out_color_counts: Vec<ColorCounts> = Vec::new();
-        out_color_counts.resize(self.nodes.len(), Default::default());
+    /// Module inputs are connected to the outside world directly, so a combinator can't read two inputs
+    /// of the same color, and an input can't be passed to an output of a different color.
+    /// A `@both` input is read on both wires, so it can't share a combinator or a wire with any other value,
+    /// or that value would be seen twice.
+    /// In those cases the argument is copied through a buffer combinator, the same as writing `+x`.
+    /// This delays it by a tick, so every wired argument of the node is buffered to keep them in step.
+    /// Buffers are shared between nodes that use a value in the same argument slot. A node that reads a value
+    /// on both inputs gets a separate buffer for each, or it would read a single node twice.
+    fn buffer_port_conflicts(&mut self, files: &[SourceFile]) {
+        let mut buffers: HashMap<(u32,usize),IRArg> = HashMap::new();
+        // Multi-drivers are handled first, since they decide which inputs the other nodes see.
+        for pass_multi_drivers in [true,false] {
+            for i in 0..self.nodes.len() {
+                let (args,reason) = match self.nodes.get(i) {
+                    IRNode::MultiDriver(args) if pass_multi_drivers && args.len() > 1 => {
+                        if !args.iter().any(|arg| self.input_color(arg) == WireColor::Both) {
+                            continue;
+                        }
+                        (args.clone(),"a `@both` input can not share a wire with other values")
+                    },
+                    IRNode::BinOp(lhs,_,rhs) |
+                    IRNode::BinOpCmpGate(lhs,_,_,rhs) if !pass_multi_drivers => {
+                        let (lhs_color,rhs_color) = (self.input_color(lhs),self.input_color(rhs));
+                        let reason = if lhs_color == WireColor::Both && rhs.is_link() || rhs_color == WireColor::Both && lhs.is_link() {
+                            "a `@both` input must be the only wired argument of a combinator"
+                        } else if lhs_color != WireColor::None && lhs_color == rhs_color {
+                            "both arguments are module inputs on the same wire color"
+                        } else {
+                            continue;
+                        };
+                        (vec!(lhs.clone(),rhs.clone()),reason)
+                    },
+                    IRNode::Output(_,arg) if !pass_multi_drivers => {
+                        let color = self.input_color(arg);
+                        if color == WireColor::None || Some(color) == self.port_color(i as u32) {
+                            continue;
+                        }
+                        (vec!(arg.clone()),"the output is connected to a module input of a different wire color")
+                    },
+                    _ => continue
+                };
 
-        let mut inputs = HashMap::new();
+                let what = if args.len() == 1 { "a buffer" } else { "buffers" };
+                println!("Note: Module '{}': Inserted {} for {}, because {}. This delays it by one tick.",self.name,what,self.nodes.describe(i,files),reason);
+                let buffered: Vec<IRArg> = args.into_iter().enumerate().map(|(slot,arg)| {
+                    let source = match arg {
+                        IRArg::Link(source,_) => source,
+                        IRArg::Constant(_) => return arg
+                    };
+                    if let Some(buffer) = buffers.get(&(source,slot)) {
+                        return buffer.clone();
+                    }
+                    let debug_name = format!("port buffer for {}",self.nodes.get_debug(source as usize));
+                    let buffer = self.add_node_at(i, IRNode::BinOp(arg,BinOp::Add,IRArg::Constant(0)), debug_name);
+                    buffers.insert((source,slot),buffer.clone());
+                    buffer
+                }).collect();
+                match self.nodes.get_mut(i) {
+                    IRNode::MultiDriver(args) => *args = buffered,
+                    node => {
+                        for (arg,buffer) in wired_args(node).into_iter().zip(buffered) {
+                            *arg = buffer;
+                        }
+                    }
+                }
+            }
+        }
+    }
 
+    /// Follows multi-drivers to find every node that drives an argument.
+    fn arg_sources(&self, arg: &IRArg, out: &mut Vec<u32>) {
+        if let IRArg::Link(parent,_) = arg {
+            if let IRNode::MultiDriver(args) = self.nodes.get(*parent as usize) {
+                for arg in args {
+                    self.arg_sources(arg, out);
+                }
+            } else {
+                out.push(*parent);
+            }
+        }
+    }
+
+    /// Assigns a wire color to every link. Each combinator must read its two arguments on different colors,
+    /// and links to module inputs and outputs use the port's color, which is red unless declared otherwise.
+    /// Within those rules, links that carry the same signals are given the same color where possible,
+    /// so they form a single network.
+    /// The files are used to show where the nodes in an error come from.
+    pub fn select_colors(&mut self, files: &[SourceFile]) -> Result<(),String> {
+        self.check_input_colors(files)?;
+        self.buffer_port_conflicts(files);
+        print!("Color selection... ");
+
+        // Collect links, grouped by the nodes that drive them.
+        let mut links: Vec<ColorLink> = Vec::new();
+        let mut groups: Vec<ColorGroup> = Vec::new();
+        let mut group_map: HashMap<Vec<u32>,usize> = HashMap::new();
+        let mut both_links = Vec::new();
         for i in 0..self.nodes.len() {
-            let node = self.nodes.get(i);
-            match node {
-                IRNode::BinOp(lhs,_,rhs) |
-                IRNode::BinOpCmpGate(lhs,_,_,rhs) => {
-                    let li = self.is_input(lhs);
-                    let ri = self.is_input(rhs);
-
-                    if li && ri {
-                        panic!("CANNOT COLOR BOTH ARGS RED: {:?} {:?}",lhs,rhs);
-                    }
+            let output_color = self.port_color(i as u32);
+            let mut args = self.nodes.get(i).clone();
+            for (slot,arg) in wired_args(&mut args).into_iter().enumerate() {
+                if !arg.is_link() {
+                    continue;
+                }
+                let mut sources = Vec::new();
+                self.arg_sources(arg, &mut sources);
+                sources.sort_unstable();
+                sources.dedup();
 
-                    if li {
-                        inputs.insert(i, 0);
-                    }
+                // Conflicts between input and output colors have already been buffered.
+                let forced = output_color.unwrap_or_else(|| self.input_color(arg));
+                if forced == WireColor::Both {
+                    // A `@both` input is the only value on its wires, so it doesn't take part in coloring.
+                    both_links.push((i,slot));
+                    continue;
+                }
+                let group = *group_map.entry(sources.clone()).or_insert_with(|| {
+                    groups.push(ColorGroup{sources, links: Vec::new(), neighbors: Vec::new(), color: WireColor::None, nets: [false;2]});
+                    groups.len() - 1
+                });
+                groups[group].links.push(links.len());
+                links.push(ColorLink{node: i, slot, group, forced});
+            }
+        }
 
-                    if ri {
-                        inputs.insert(i, 1);
+        // Find groups that must not share a color.
+        let mut partners: HashMap<usize,Vec<usize>> = HashMap::new();
+        for (link_i,link) in links.iter().enumerate() {
+            partners.entry(link.node).or_default().push(link_i);
+        }
+        let mut source_groups: HashMap<u32,Vec<usize>> = HashMap::new();
+        for (group_i,group) in groups.iter().enumerate() {
+            for source in &group.sources {
+                source_groups.entry(*source).or_default().push(group_i);
+            }
+        }
+        let mut neighbors: Vec<Vec<usize>> = vec!(Vec::new(); groups.len());
+        for node_links in partners.values() {
+            if let [a,b] = node_links[..] {
+                neighbors[links[a].group].push(links[b].group);
+                neighbors[links[b].group].push(links[a].group);
+            }
+        }
+        for sharing in source_groups.values() {
+            for a in sharing {
+                for b in sharing {
+                    if a != b {
+                        neighbors[*a].push(*b);
                     }
-                },
-                IRNode::BinOpSame(arg,_) => {
-                    if self.is_input(arg) {
-                        inputs.insert(i, 0);
+                }
+            }
+        }
+        for (group,mut list) in groups.iter_mut().zip(neighbors) {
+            list.sort_unstable();
+            list.dedup();
+            group.neighbors = list;
+        }
+
+        // Two-color the groups breadth first, starting from the ones connected to ports.
+        // A group whose neighbors already use both colors, or that connects to ports of both colors,
+        // is left to be colored link by link.
+        let mut group_forced = vec!([false;2]; groups.len());
+        for link in &links {
+            if link.forced != WireColor::None {
+                group_forced[link.group][color_index(link.forced)] = true;
+            }
+        }
+        let mut queue: VecDeque<usize> = (0..groups.len()).filter(|i| group_forced[*i] != [false;2]).collect();
+        let mut visited = vec!(false; groups.len());
+        let mut next_start = 0;
+        loop {
+            let group_i = if let Some(group_i) = queue.pop_front() {
+                group_i
+            } else if let Some(start) = (next_start..groups.len()).find(|i| !visited[*i]) {
+                next_start = start;
+                start
+            } else {
+                break;
+            };
+            if visited[group_i] {
+                continue;
+            }
+            visited[group_i] = true;
+
+            let mut used = [false;2];
+            for neighbor in &groups[group_i].neighbors {
+                if groups[*neighbor].color != WireColor::None {
+                    used[color_index(groups[*neighbor].color)] = true;
+                }
+            }
+            groups[group_i].color = match (group_forced[group_i],used) {
+                ([true,false],[false,_]) => WireColor::Red,
+                ([false,true],[_,false]) => WireColor::Green,
+                ([false,false],[false,_]) => WireColor::Red,
+                ([false,false],[true,false]) => WireColor::Green,
+                _ => WireColor::None
+            };
+            for neighbor in &groups[group_i].neighbors {
+                if !visited[*neighbor] {
+                    queue.push_back(*neighbor);
+                }
+            }
+        }
+
+        // Pick the color of each link.
+        let mut link_colors = vec!(WireColor::None; links.len());
+        for (link_i,link) in links.iter().enumerate() {
+            let color = groups[link.group].color;
+            if color != WireColor::None {
+                link_colors[link_i] = color;
+                groups[link.group].nets[color_index(color)] = true;
+            }
+        }
+        for link_i in 0..links.len() {
+            if link_colors[link_i] != WireColor::None {
+                continue;
+            }
+            let link = &links[link_i];
+            let group = &groups[link.group];
+
+            let mut allowed = match link.forced {
+                WireColor::None => [true,true],
+                forced => [forced == WireColor::Red,forced == WireColor::Green]
+            };
+            for partner in &partners[&link.node] {
+                if *partner != link_i && link_colors[*partner] != WireColor::None {
+                    allowed[color_index(link_colors[*partner])] = false;
+                }
+            }
+            for neighbor in &group.neighbors {
+                let shares_source = groups[*neighbor].sources.iter().any(|x| group.sources.contains(x));
+                if shares_source {
+                    for (allow,used) in allowed.iter_mut().zip(groups[*neighbor].nets.iter()) {
+                        *allow &= !used;
                     }
-                },
-                _ => ()
+                }
             }
+
+            // Prefer a color this group already has a network on.
+            let color = [WireColor::Red,WireColor::Green].iter().copied()
+                .filter(|c| allowed[color_index(*c)])
+                .max_by_key(|c| (group.nets[color_index(*c)],*c == WireColor::Red))
+                .ok_or_else(|| format!("Module '{}': Can not pick wire colors for {} without merging networks that must stay separate.",
+                    self.name,self.nodes.describe(link.node,files)))?;
+            link_colors[link_i] = color;
+            groups[link.group].nets[color_index(color)] = true;
         }
 
-        for i in 0..self.nodes.len() {
-            let node = self.nodes.get_mut(i);
-            match node {
-                IRNode::Input(_) => (),
-                IRNode::Constant(_) => (),
-                IRNode::Removed => (),
-                IRNode::BinOp(lhs,_,rhs) |
-                IRNode::BinOpCmpGate(lhs,_,_,rhs) => {
-                    let input_side = inputs.get(&i);
-                    let forbid_color = match input_side {
-                        Some(0) => WireColor::Green,
-                        Some(1) => WireColor::Red,
-                        _ => WireColor::None
-                    };
-                    let forbid_color = update_color_for_arg(lhs, forbid_color, &mut out_color_counts);
-                    update_color_for_arg(rhs , forbid_color, &mut out_color_counts);
-                },
-                IRNode::BinOpSame(arg,_) => {
-                    let input_side = inputs.get(&i);
-                    let forbid_color = if input_side.is_some() { WireColor::Green } else { WireColor::None };
-                    update_color_for_arg(arg, forbid_color, &mut out_color_counts);
-                },
-                IRNode::Output(_,arg) => {
-                    // Force red outputs
-                    update_color_for_arg(arg , WireColor::Green, &mut out_color_counts);
-                },
-                IRNode::MultiDriver(_) => (), // use colors determined by downstream nodes
-                _ => panic!("Node {:?} is not supported at this stage.",node)
+        let link_colors = links.iter().map(|link| (link.node,link.slot)).zip(link_colors)
+            .chain(both_links.into_iter().map(|link| (link,WireColor::Both)));
+        for ((node,slot),color) in link_colors {
+            if let IRArg::Link(_,arg_color) = wired_args(self.nodes.get_mut(node)).swap_remove(slot) {
+                *arg_color = color;
+            }
+        }
+
+        let nets: usize = groups.iter().map(|group| group.nets.iter().filter(|x| **x).count()).sum();
+        println!("Done, {} networks.",nets);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_util;
+
+    /// The wired arguments of each node, as the node they come from and their color.
+    fn node_links(module: &IRModule) -> Vec<Vec<(u32,WireColor)>> {
+        module.nodes.iter().map(|node| {
+            wired_args(&mut node.clone()).into_iter().filter_map(|arg| match arg {
+                IRArg::Link(source,color) => Some((*source,*color)),
+                IRArg::Constant(_) => None
+            }).collect()
+        }).collect()
+    }
+
+    fn binding(module: &IRModule, name: &str) -> u32 {
+        match module.bindings.get(name) {
+            Some(IRArg::Link(id,_)) => *id,
+            _ => panic!("'{}' is not bound to a node",name)
+        }
+    }
+
+    #[test]
+    fn gate_reads_input_on_both_sides() {
+        let symbols = test_util::symbols();
+        test_util::compile(&symbols,"mod main(x,y) { output(match(y) { 1 => x, 3 => y }); }","main").unwrap();
+    }
+
+    #[test]
+    fn gate_reads_input_on_both_sides_with_both_input() {
+        let symbols = test_util::symbols();
+        test_util::compile(&symbols,"mod main(x, z @both) { output(match(x) { 2 => z, 3 => x }); }","main").unwrap();
+    }
+
+    #[test]
+    fn values_keep_one_network() {
+        let symbols = test_util::symbols();
+        let source = "mod main(a) {\n    let x = a * 2;\n    let y = a * 3;\n    output(x + y, x - y, x * y);\n}";
+        let module = test_util::compile(&symbols,source,"main").unwrap();
+        let links = node_links(&module);
+        for node in &links {
+            if let [(_,lhs),(_,rhs)] = node[..] {
+                assert_ne!(lhs,rhs);
+            }
+        }
+
+        // Every combinator reading x and y is on the same two networks.
+        let (x,y) = (binding(&module,"x"),binding(&module,"y"));
+        let color_of = |source: u32| {
+            let mut colors: Vec<WireColor> = links.iter().flatten().filter(|(other,_)| *other == source).map(|(_,color)| *color).collect();
+            colors.dedup();
+            assert_eq!(colors.len(),1,"{:?}",colors);
+            colors[0]
+        };
+        assert_ne!(color_of(x),color_of(y));
+    }
+
+    #[test]
+    fn both_input_is_read_alone() {
+        let symbols = test_util::symbols();
+        let source = "mod main(a @both, b) {\n    output(a * 2 + b, a + b);\n}";
+        let module = test_util::compile(&symbols,source,"main").unwrap();
+        let a = binding(&module,"a");
+        let mut readers = 0;
+        for node in node_links(&module) {
+            if node.iter().any(|(source,_)| *source == a) {
+                // `a + b` reads it through a buffer instead.
+                assert_eq!(node,[(a,WireColor::Both)]);
+                readers += 1;
             }
         }
+        assert_eq!(readers,2);
     }
 }
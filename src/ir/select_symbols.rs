@@ -1,21 +1,45 @@
 
+use std::collections::{HashSet, VecDeque};
+
 use crate::disjoint_set::DisjointSet;
+use crate::loader::SourceFile;
+use crate::symbols::SymbolTable;
 
 use super::{IRModule, IRNode, IRArg};
 
 #[derive(Debug)]
 enum SymbolConstraint {
-    Equal(u32,u32),
+    Equal(u32,u32,EqualReason),
     NotEqual(u32,u32),
-    EqualSymbol(u32,u32)
+    EqualSymbol(u32,u32,EqualReason)
+}
+
+/// Why two nodes, or a node and a declared type, must use the same signal. Used to explain conflicts.
+#[derive(Debug,Clone,Copy)]
+enum EqualReason {
+    /// Both drive the same wire.
+    MultiDriver,
+    /// A gate passes its input through unchanged.
+    GatePassThrough,
+    /// The module's input port has a declared type.
+    TypedInput(u32),
+    /// The module's output port has a declared type.
+    TypedOutput(u32),
+    /// An output port without a declared type is labeled with the signal it carries.
+    UntypedOutput
 }
 
 impl IRModule {
-    pub fn select_symbols(&mut self) {
+    /// Picks a signal for every node. Fails if the constraints on the signals conflict,
+    /// or if the design does not fit in the signals allowed by --max-signals.
+    /// The files are used to show where the nodes in a conflict come from.
+    pub fn select_symbols(&mut self, symbols: &SymbolTable, files: &[SourceFile]) -> Result<(),String> {
         print!("Symbol selection... ");
 
         // Set up symbol vector.
-        self.out_symbols.resize(self.nodes.len(),0);
+        let available = symbols.auto_symbols();
+        let first_symbol = *available.first().expect("no signals are available for automatic allocation");
+        self.out_symbols.resize(self.nodes.len(),first_symbol);
 
         let mut constraints: Vec<SymbolConstraint> = Vec::new();
 
@@ -23,17 +47,18 @@ impl IRModule {
             match node {
                 IRNode::Input(arg_n) => {
                     if let Some(sym) = self.arg_types[*arg_n as usize] {
-                        constraints.push(SymbolConstraint::EqualSymbol(out_i as u32,sym));
+                        constraints.push(SymbolConstraint::EqualSymbol(out_i as u32,sym,EqualReason::TypedInput(*arg_n)));
                     }
                 },
                 IRNode::Output(n,arg) => {
-                    if let Some(ret_types) = &self.ret_types {
-                        if let Some(sym) = ret_types[*n as usize] {
-                            constraints.push(SymbolConstraint::EqualSymbol(out_i as u32,sym));
-                            if let IRArg::Link(arg_i,_) = arg {
-                                constraints.push(SymbolConstraint::EqualSymbol(*arg_i,sym));
-                            }
+                    let ret_type = self.ret_types.as_ref().and_then(|ret_types| ret_types[*n as usize]);
+                    if let Some(sym) = ret_type {
+                        constraints.push(SymbolConstraint::EqualSymbol(out_i as u32,sym,EqualReason::TypedOutput(*n)));
+                        if let IRArg::Link(arg_i,_) = arg {
+                            constraints.push(SymbolConstraint::EqualSymbol(*arg_i,sym,EqualReason::TypedOutput(*n)));
                         }
+                    } else if let IRArg::Link(arg_i,_) = arg {
+                        constraints.push(SymbolConstraint::Equal(*arg_i,out_i as u32,EqualReason::UntypedOutput));
                     }
                 },
                 IRNode::Constant(_) => (),
@@ -52,7 +77,7 @@ impl IRModule {
                     if let IRArg::Link(lhs_in,_) = lhs {
                         if let IRArg::Link(gated_in,_) = gated {
                             constraints.push(SymbolConstraint::NotEqual(*lhs_in,*gated_in));
-                            constraints.push(SymbolConstraint::Equal(*gated_in,out_i as u32));
+                            constraints.push(SymbolConstraint::Equal(*gated_in,out_i as u32,EqualReason::GatePassThrough));
                         }
                     }
                 },
@@ -60,7 +85,7 @@ impl IRModule {
                     // all input symbols must match
                     for arg in list {
                         if let IRArg::Link(arg_in,_) = arg {
-                            constraints.push(SymbolConstraint::Equal(*arg_in,out_i as u32));
+                            constraints.push(SymbolConstraint::Equal(*arg_in,out_i as u32,EqualReason::MultiDriver));
                         }
                     }
                 },
@@ -71,90 +96,283 @@ impl IRModule {
         // Build equal sets.
         let mut equal_sets = DisjointSet::new(self.nodes.len());
         for cons in &constraints {
-            match cons {
-                SymbolConstraint::Equal(a,b) => {
-                    equal_sets.merge(*a as usize, *b as usize);
-                },
-                _ => ()
+            if let SymbolConstraint::Equal(a,b,_) = cons {
+                equal_sets.merge(*a as usize, *b as usize);
             }
         }
 
         // A secondary vector that indicates a symbol is pinned and unable to be changed without violating a constraint.
         let mut pinned_symbols = Vec::new();
         pinned_symbols.resize(self.nodes.len(),false);
+        // The constraint that pinned each set, used to explain conflicts.
+        let mut pinned_by: Vec<Option<usize>> = vec!(None; self.nodes.len());
 
-        // Panic in the event of un-solvable constraints.
+        // Fail in the event of un-solvable constraints.
+        for (cons_i,cons) in constraints.iter().enumerate() {
+            if let SymbolConstraint::EqualSymbol(index,sym,reason) = cons {
+                let set = equal_sets.get(*index as usize);
+                if pinned_symbols[set] && self.out_symbols[set] != *sym {
+                    let mut lines = vec!(format!("Module '{}': Conflicting equality and type signature constraints. ({}) must be both {} and {}:",
+                        self.name,self.nodes.get_debug(*index as usize),symbols.symbol_name(self.out_symbols[set]),symbols.symbol_name(*sym)));
+                    lines.extend(self.explain_pinned(&constraints,pinned_by[set].unwrap(),*index,symbols,files));
+                    lines.push(self.explain_equal_symbol(*index,*sym,*reason,symbols,files));
+                    return Err(lines.join("\n"));
+                }
+                if !pinned_symbols[set] {
+                    pinned_by[set] = Some(cons_i);
+                }
+                pinned_symbols[set] = true;
+                self.out_symbols[set] = *sym;
+            }
+        }
         for cons in &constraints {
-            match cons {
-                SymbolConstraint::EqualSymbol(index,sym) => {
-                    let set = equal_sets.get(*index as usize);
-                    if pinned_symbols[set] && self.out_symbols[set] != *sym {
-                        panic!("Conflicting equality and type signature constraints.");
-                    }
-                    pinned_symbols[set] = true;
-                    self.out_symbols[set] = *sym;
-                },
-                _ => ()
+            if let SymbolConstraint::NotEqual(a,b) = cons {
+                let set_a = equal_sets.get(*a as usize);
+                let set_b = equal_sets.get(*b as usize);
+                if set_a == set_b {
+                    let mut lines = vec!(format!("Module '{}': Conflicting equality and inequality constraints. ({}) and ({}) are inputs to the same combinator, so they must use different signals, but they are forced to be equal:",
+                        self.name,self.nodes.get_debug(*a as usize),self.nodes.get_debug(*b as usize)));
+                    lines.extend(self.explain_equal(&constraints,*a,*b,files));
+                    return Err(lines.join("\n"));
+                }
+                if pinned_symbols[set_a] && pinned_symbols[set_b] && self.out_symbols[set_a] == self.out_symbols[set_b] {
+                    let mut lines = vec!(format!("Module '{}': Conflicting inequality and type signature constraints. ({}) and ({}) are inputs to the same combinator, so they must use different signals, but both are {}:",
+                        self.name,self.nodes.get_debug(*a as usize),self.nodes.get_debug(*b as usize),symbols.symbol_name(self.out_symbols[set_a])));
+                    lines.extend(self.explain_pinned(&constraints,pinned_by[set_a].unwrap(),*a,symbols,files));
+                    lines.extend(self.explain_pinned(&constraints,pinned_by[set_b].unwrap(),*b,symbols,files));
+                    return Err(lines.join("\n"));
+                }
             }
         }
+
+        // Build the inequality graph, with one vertex per equal set.
+        let mut neighbors: Vec<Vec<usize>> = vec!(Vec::new(); self.nodes.len());
         for cons in &constraints {
-            match cons {
-                SymbolConstraint::NotEqual(a,b) => {
-                    let set_a = equal_sets.get(*a as usize);
-                    let set_b = equal_sets.get(*b as usize);
-                    if set_a == set_b {
-                        let dbg_a = self.nodes.get_debug(*a as usize);
-                        let dbg_b = self.nodes.get_debug(*b as usize);
-                        println!("First: ({}) != ({})",dbg_a,dbg_b);
-                        panic!("Conflicting equality and inequality constraints.");
-                    }
-                    if pinned_symbols[set_a] && pinned_symbols[set_b] {
-                        if self.out_symbols[set_a] == self.out_symbols[set_b] {
-                            panic!("Conflicting inequality and type signature constraints.");
-                        }
-                    }
-                },
-                _ => ()
+            if let SymbolConstraint::NotEqual(a,b) = cons {
+                let set_a = equal_sets.get(*a as usize);
+                let set_b = equal_sets.get(*b as usize);
+                neighbors[set_a].push(set_b);
+                neighbors[set_b].push(set_a);
             }
         }
+        for list in &mut neighbors {
+            list.sort_unstable();
+            list.dedup();
+        }
 
-        // Fix inequalities
-        let mut pass_num = 1;
-        let mut errors = 0;
-        loop {
-            for cons in &constraints {
-                match cons {
-                    SymbolConstraint::NotEqual(a,b) => {
-                        let set_a = equal_sets.get(*a as usize);
-                        let set_b = equal_sets.get(*b as usize);
-
-                        if self.out_symbols[set_a] == self.out_symbols[set_b] {
-                            errors += 1;
-                            if !pinned_symbols[set_a] {
-                                self.out_symbols[set_a] += 1;
-                            } else if !pinned_symbols[set_b] {
-                                self.out_symbols[set_b] += 1;
-                            } else {
-                                panic!("two symbols pinned");
-                            }
-                        }
-                    },
-                    _ => ()
+        let sets: Vec<usize> = (0..self.nodes.len())
+            .filter(|set| equal_sets.get(*set) == *set && !pinned_symbols[*set])
+            .collect();
+
+        let mut palette = available;
+        if self.settings.min_signals {
+            // Signals the design already uses because of type signatures are free to reuse, so they are tried first.
+            let mut pinned: Vec<u32> = Vec::new();
+            for (sym,_) in self.out_symbols.iter().zip(pinned_symbols.iter()).filter(|(_,pinned)| **pinned) {
+                if palette.contains(sym) && !pinned.contains(sym) {
+                    pinned.push(*sym);
                 }
             }
-            if errors == 0 {
-                break;
+            palette.retain(|sym| !pinned.contains(sym));
+            pinned.append(&mut palette);
+            palette = pinned;
+        }
+
+        if let Some(max) = self.settings.max_signals {
+            // Signals pinned by type signatures count against the limit, whether or not they could be allocated.
+            let mut pinned: Vec<u32> = self.out_symbols.iter().zip(pinned_symbols.iter())
+                .filter(|(_,pinned)| **pinned)
+                .map(|(sym,_)| *sym)
+                .collect();
+            pinned.sort_unstable();
+            pinned.dedup();
+            let pinned_outside = pinned.iter().filter(|sym| !palette.contains(sym)).count();
+            if pinned.len() > max as usize {
+                return Err(format!("Module '{}': The type signatures alone use {} distinct signals, but --max-signals only allows {}.",
+                    self.name,pinned.len(),max));
             }
-            pass_num += 1;
-            errors = 0;
+            palette.truncate(max as usize - pinned_outside);
         }
 
+        let mut best = match color_sets(&sets,&neighbors,&pinned_symbols,&self.out_symbols,&palette) {
+            Ok(coloring) => coloring,
+            Err(set) => match self.settings.max_signals {
+                Some(max) => return Err(format!("Module '{}': The design does not fit in the {} signal(s) allowed by --max-signals. ({}) must differ from {} other value(s), which already use all of them.",
+                    self.name,max,self.nodes.get_debug(set),neighbors[set].len())),
+                None => return Err(self.report_symbol_shortage(set,&neighbors[set],palette.len()))
+            }
+        };
+        if self.settings.min_signals {
+            // Keep retrying with the last signal the previous coloring needed taken away, until it no longer fits.
+            loop {
+                let needed = sets.iter().filter_map(|set| palette.iter().position(|sym| *sym == best[*set])).max();
+                let limit = match needed {
+                    Some(n) if n > 0 => n,
+                    _ => break
+                };
+                match color_sets(&sets,&neighbors,&pinned_symbols,&self.out_symbols,&palette[..limit]) {
+                    Ok(coloring) => best = coloring,
+                    Err(_) => break
+                }
+            }
+        }
+        self.out_symbols = best;
+
         // Correct the signal id for all nodes.
         for i in 0..self.nodes.len() {
             let set_i = equal_sets.get(i);
             self.out_symbols[i] = self.out_symbols[set_i];
         }
 
-        println!("Done in {} passes.",pass_num);
+        let used: HashSet<u32> = self.nodes.iter().zip(self.out_symbols.iter())
+            .filter(|(node,_)| **node != IRNode::Removed)
+            .map(|(_,sym)| *sym)
+            .collect();
+        println!("Done, {} signals used.",used.len());
+        Ok(())
+    }
+
+    /// Explains why a node has a signal: the type signature that pinned its set, then the chain of equalities leading to the node.
+    fn explain_pinned(&self, constraints: &[SymbolConstraint], pin_i: usize, node: u32, symbols: &SymbolTable, files: &[SourceFile]) -> Vec<String> {
+        if let SymbolConstraint::EqualSymbol(pinned,sym,reason) = &constraints[pin_i] {
+            let mut lines = vec!(self.explain_equal_symbol(*pinned,*sym,*reason,symbols,files));
+            lines.extend(self.explain_equal(constraints,*pinned,node,files));
+            lines
+        } else {
+            panic!("set pinned by a constraint that is not EqualSymbol");
+        }
+    }
+
+    fn explain_equal_symbol(&self, node: u32, sym: u32, reason: EqualReason, symbols: &SymbolTable, files: &[SourceFile]) -> String {
+        let port = match reason {
+            EqualReason::TypedInput(n) => format!("input {}",n),
+            EqualReason::TypedOutput(n) => format!("output {}",n),
+            _ => panic!("type signature constraint with reason {:?}",reason)
+        };
+        self.explain_line(node, files, format!("({}) is {}, because {} of '{}' is declared as {}",
+            self.nodes.get_debug(node as usize),symbols.symbol_name(sym),port,self.name,symbols.symbol_name(sym)))
+    }
+
+    /// Finds the chain of equality constraints that connects two nodes, with one line per link.
+    fn explain_equal(&self, constraints: &[SymbolConstraint], from: u32, to: u32, files: &[SourceFile]) -> Vec<String> {
+        let mut edges: Vec<Vec<(u32,EqualReason)>> = vec!(Vec::new(); self.nodes.len());
+        for cons in constraints {
+            if let SymbolConstraint::Equal(a,b,reason) = cons {
+                edges[*a as usize].push((*b,*reason));
+                edges[*b as usize].push((*a,*reason));
+            }
+        }
+
+        // Breadth-first search, so the shortest chain is shown.
+        let mut came_from: Vec<Option<(u32,EqualReason)>> = vec!(None; self.nodes.len());
+        let mut queue = VecDeque::new();
+        queue.push_back(from);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                break;
+            }
+            for (next,reason) in &edges[node as usize] {
+                if *next != from && came_from[*next as usize].is_none() {
+                    came_from[*next as usize] = Some((node,*reason));
+                    queue.push_back(*next);
+                }
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut node = to;
+        while let Some((prev,reason)) = came_from[node as usize] {
+            let (a,b) = (self.nodes.get_debug(prev as usize),self.nodes.get_debug(node as usize));
+            let text = match reason {
+                EqualReason::MultiDriver => format!("({}) has the same signal as ({}), because they drive the same wire",b,a),
+                EqualReason::GatePassThrough => format!("({}) has the same signal as ({}), because a gate passes it through unchanged",b,a),
+                EqualReason::UntypedOutput => format!("({}) has the same signal as ({}), because the output port is labeled with it",b,a),
+                _ => format!("({}) has the same signal as ({})",b,a)
+            };
+            lines.push(self.explain_line(node, files, text));
+            node = prev;
+        }
+        lines.reverse();
+        lines
+    }
+
+    /// Indents a line of an explanation, and starts it with where the node it is about comes from.
+    fn explain_line(&self, node: u32, files: &[SourceFile], text: String) -> String {
+        match self.nodes.location(node as usize) {
+            Some(location) => format!("    {}: {}",location.describe(files),text),
+            None => format!("    {}",text)
+        }
+    }
+
+    /// Describes the values that need more distinct signals than are available.
+    fn report_symbol_shortage(&self, set: usize, neighbors: &[usize], available: usize) -> String {
+        const MAX_LISTED: usize = 10;
+        let mut names: Vec<String> = neighbors.iter().take(MAX_LISTED).map(|n| self.nodes.get_debug(*n)).collect();
+        if neighbors.len() > MAX_LISTED {
+            names.push("...".to_owned());
+        }
+        format!("Module '{}': Ran out of signals to allocate. ({}) must differ from {} other values, which already use all {} available signals: ({}). Allow more categories with --auto-signals.",
+            self.name,self.nodes.get_debug(set),neighbors.len(),available,names.join("), ("))
+    }
+}
+
+/// Colors the inequality graph with DSatur: the set with the most distinct symbols among its neighbors
+/// has the fewest options left, so it is colored next. Each set gets the first symbol in the palette
+/// that none of its neighbors use. On failure, returns a set that could not be colored.
+fn color_sets(sets: &[usize], neighbors: &[Vec<usize>], pinned: &[bool], symbols: &[u32], palette: &[u32]) -> Result<Vec<u32>,usize> {
+    let mut symbols = symbols.to_vec();
+
+    // The symbols already used by each set's neighbors. Pinned sets are colored from the start.
+    let mut neighbor_symbols: Vec<HashSet<u32>> = vec!(HashSet::new(); symbols.len());
+    for set in 0..symbols.len() {
+        if pinned[set] {
+            for neighbor in &neighbors[set] {
+                neighbor_symbols[*neighbor].insert(symbols[set]);
+            }
+        }
+    }
+
+    let mut uncolored = sets.to_vec();
+    while !uncolored.is_empty() {
+        let (pick_i,&set) = uncolored.iter().enumerate()
+            .max_by_key(|(_,set)| (neighbor_symbols[**set].len(),neighbors[**set].len()))
+            .unwrap();
+        uncolored.swap_remove(pick_i);
+
+        let symbol = palette.iter().copied().find(|sym| !neighbor_symbols[set].contains(sym)).ok_or(set)?;
+        symbols[set] = symbol;
+        for neighbor in &neighbors[set] {
+            neighbor_symbols[*neighbor].insert(symbol);
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::SymbolTable;
+    use super::super::test_util;
+
+    #[test]
+    fn conflicting_type_signatures() {
+        let symbols = test_util::symbols();
+        let err = test_util::compile(&symbols,"mod main(a) -> ($A,$B) {\n    output(a,a);\n}","main").unwrap_err();
+        assert!(err.starts_with("Module 'main': Conflicting equality and type signature constraints. (arg a) must be both $A and $B:"),"{}",err);
+        assert!(err.contains("test.cdl:1:10: (arg a) is $A, because output 0"),"{}",err);
+        assert!(err.contains("test.cdl:1:10: (arg a) is $B, because output 1"),"{}",err);
+    }
+
+    #[test]
+    fn symbol_shortage() {
+        let mut symbols = SymbolTable::default();
+        symbols.add_json(r#"[
+            {"id": "A", "signal": {"type": "virtual", "name": "signal-A"}},
+            {"id": "B", "signal": {"type": "virtual", "name": "signal-B"}}
+        ]"#).unwrap();
+        // Each pair of values is added, so all three need different signals.
+        let source = "mod main(a) {\n    let x = a * 2;\n    let y = a * 3;\n    let z = a * 4;\n    output(x + y, y + z, x + z);\n}";
+        let err = test_util::compile(&symbols,source,"main").unwrap_err();
+        assert!(err.starts_with("Module 'main': Ran out of signals to allocate."),"{}",err);
+        assert!(err.contains("which already use all 2 available signals"),"{}",err);
     }
 }
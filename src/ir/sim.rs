@@ -0,0 +1,325 @@
+// Runs a module tick by tick, the way its combinators would run in game.
+
+use super::{IRArg, IRModule, IRNode};
+
+/// Simulates a built module.
+///
+/// Every combinator takes one tick to update its output. Multi-drivers are just wires, so they
+/// sum their drivers within the same tick. Wire colors are not modeled: every link carries the
+/// value of the node driving it, as if each node had its own signal.
+pub struct Simulator<'m> {
+    module: &'m IRModule,
+    /// The outputs of combinators, which only change when the simulation steps.
+    outputs: Vec<i32>,
+    /// The value of every node for the current tick.
+    values: Vec<i32>,
+    /// Values driven onto the input ports.
+    inputs: Vec<i32>,
+    /// Nodes which update within a tick, ordered so every node comes after the nodes it reads.
+    wire_order: Vec<usize>,
+    /// The node for each output port.
+    output_nodes: Vec<usize>,
+    tick: u32
+}
+
+impl<'m> Simulator<'m> {
+    pub fn new(module: &'m IRModule) -> Self {
+        let node_count = module.nodes.len();
+        let mut output_nodes = Vec::new();
+        let mut input_count = 0;
+        for (i,node) in module.nodes.iter().enumerate() {
+            match node {
+                IRNode::Input(n) => input_count = input_count.max(*n as usize + 1),
+                IRNode::Output(n,_) => {
+                    let n = *n as usize;
+                    if n >= output_nodes.len() {
+                        output_nodes.resize(n + 1, usize::MAX);
+                    }
+                    output_nodes[n] = i;
+                },
+                IRNode::Gate(..) | IRNode::PlaceHolder => {
+                    panic!("Module '{}': Node {:?} is not supported at this stage.",module.name,node);
+                },
+                _ => ()
+            }
+        }
+
+        let mut sim = Simulator{
+            module,
+            outputs: vec![0; node_count],
+            values: vec![0; node_count],
+            inputs: vec![0; input_count],
+            wire_order: wire_order(module),
+            output_nodes,
+            tick: 0
+        };
+        sim.settle();
+        sim
+    }
+
+    /// Clears all combinator outputs and inputs, as if the blueprint was just placed.
+    pub fn reset(&mut self) {
+        self.outputs.iter_mut().for_each(|x| *x = 0);
+        self.inputs.iter_mut().for_each(|x| *x = 0);
+        self.tick = 0;
+        self.settle();
+    }
+
+    /// The number of ticks run since the simulation started.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn output_count(&self) -> usize {
+        self.output_nodes.len()
+    }
+
+    /// Drives an input port. Combinators reading it will see the new value this tick.
+    pub fn set_input(&mut self, port: usize, value: i32) {
+        self.inputs[port] = value;
+        self.settle();
+    }
+
+    pub fn get_output(&self, port: usize) -> i32 {
+        self.values[self.output_nodes[port]]
+    }
+
+    /// The value a node outputs during the current tick.
+    pub fn get_node(&self, id: usize) -> i32 {
+        self.values[id]
+    }
+
+    /// Finds the port number of an input, by the name it is bound to, like `x` or `V[3]`.
+    pub fn find_input(&self, key: &str) -> Result<usize,String> {
+        if let Some(IRArg::Link(id,_)) = self.module.bindings.get(key) {
+            if let IRNode::Input(n) = self.module.nodes.get(*id as usize) {
+                return Ok(*n as usize);
+            }
+        }
+        Err(format!("'{}' is not an input of module '{}'.",key,self.module.name))
+    }
+
+    /// Reads an input, binding, or output by name. `out` is the first output, and `out[n]` is output n.
+    pub fn read(&self, key: &str) -> Result<i32,String> {
+        if let Some(arg) = self.module.bindings.get(key) {
+            if let IRArg::Link(id,_) = arg {
+                if matches!(self.module.nodes.get(*id as usize), IRNode::Removed) {
+                    return Err(format!("'{}' is not used by any output of module '{}', so it was removed.",key,self.module.name));
+                }
+            }
+            return Ok(self.arg(arg));
+        }
+        let port = if key == "out" {
+            Some(0)
+        } else {
+            key.strip_prefix("out[").and_then(|rest| rest.strip_suffix(']')).and_then(|n| n.parse::<usize>().ok())
+        };
+        match port {
+            Some(port) if port < self.output_count() => Ok(self.get_output(port)),
+            Some(port) => Err(format!("Module '{}' does not have an output {}.",self.module.name,port)),
+            None => Err(format!("'{}' is not an input, binding, or output of module '{}'.",key,self.module.name))
+        }
+    }
+
+    /// Runs a single tick. Every combinator reads its inputs and updates its output.
+    pub fn step(&mut self) {
+        for (i,node) in self.module.nodes.iter().enumerate() {
+            let res = match node {
+                IRNode::BinOp(lhs,op,rhs) => op.fold(self.arg(lhs),self.arg(rhs)),
+                IRNode::BinOpSame(arg,op) => {
+                    let val = self.arg(arg);
+                    op.fold(val,val)
+                },
+                IRNode::BinOpCmpGate(lhs,op,rhs,gated) => {
+                    if op.fold(self.arg(lhs),*rhs) != 0 {
+                        self.arg(gated)
+                    } else {
+                        0
+                    }
+                },
+                _ => continue
+            };
+            self.outputs[i] = res;
+        }
+        self.tick += 1;
+        self.settle();
+    }
+
+    fn arg(&self, arg: &IRArg) -> i32 {
+        match arg {
+            IRArg::Link(id,_) => self.values[*id as usize],
+            IRArg::Constant(n) => *n
+        }
+    }
+
+    /// Updates the values of everything that doesn't wait a tick: constants, inputs, and wires.
+    fn settle(&mut self) {
+        self.values.copy_from_slice(&self.outputs);
+        for &i in &self.wire_order {
+            self.values[i] = match self.module.nodes.get(i) {
+                IRNode::Input(n) => self.inputs[*n as usize],
+                IRNode::Constant(n) => *n,
+                IRNode::Output(_,arg) => self.arg(arg),
+                IRNode::MultiDriver(args) => args.iter().fold(0, |sum,arg| sum.wrapping_add(self.arg(arg))),
+                _ => 0
+            };
+        }
+    }
+}
+
+fn is_wire(node: &IRNode) -> bool {
+    matches!(node, IRNode::Input(..) | IRNode::Constant(..) | IRNode::Output(..) | IRNode::MultiDriver(..))
+}
+
+/// Orders the nodes that update within a tick so each one comes after everything it reads.
+/// Loops made only of wires have already been rejected by `fix_nodes`.
+fn wire_order(module: &IRModule) -> Vec<usize> {
+    let nodes = &module.nodes;
+    let mut order = Vec::new();
+    let mut visited = vec![false; nodes.len()];
+    let mut stack: Vec<(usize,bool)> = Vec::new();
+
+    for root in 0..nodes.len() {
+        if !is_wire(nodes.get(root)) {
+            continue;
+        }
+        stack.push((root,false));
+        while let Some((i,children_done)) = stack.pop() {
+            if children_done {
+                order.push(i);
+                continue;
+            }
+            if visited[i] {
+                continue;
+            }
+            visited[i] = true;
+            stack.push((i,true));
+
+            let args: &[IRArg] = match nodes.get(i) {
+                IRNode::MultiDriver(args) => args,
+                IRNode::Output(_,arg) => std::slice::from_ref(arg),
+                _ => &[]
+            };
+            for arg in args {
+                if let IRArg::Link(id,_) = arg {
+                    let id = *id as usize;
+                    if !visited[id] && is_wire(nodes.get(id)) {
+                        stack.push((id,false));
+                    }
+                }
+            }
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::BinOp;
+    use super::*;
+    use super::super::{WireColor, test_util};
+
+    fn module(nodes: Vec<IRNode>) -> IRModule {
+        let mut module = IRModule::new("test".to_owned(),test_util::settings("test"));
+        for node in nodes {
+            module.nodes.push(node,String::new());
+        }
+        module
+    }
+
+    fn link(id: u32) -> IRArg {
+        IRArg::Link(id,WireColor::None)
+    }
+
+    #[test]
+    fn combinators_take_one_tick() {
+        let module = module(vec!(
+            IRNode::Input(0),
+            IRNode::BinOp(link(0),BinOp::Add,IRArg::Constant(1)),
+            IRNode::BinOp(link(1),BinOp::Mul,IRArg::Constant(2)),
+            IRNode::Output(0,link(2))
+        ));
+        let mut sim = Simulator::new(&module);
+        sim.set_input(0,5);
+        assert_eq!(sim.get_output(0),0);
+        sim.step();
+        assert_eq!(sim.get_node(1),6);
+        assert_eq!(sim.get_output(0),0);
+        sim.step();
+        assert_eq!(sim.get_output(0),12);
+        assert_eq!(sim.tick(),2);
+    }
+
+    #[test]
+    fn wires_sum_their_drivers() {
+        let module = module(vec!(
+            IRNode::Input(0),
+            IRNode::Input(1),
+            IRNode::Constant(3),
+            IRNode::MultiDriver(vec!(link(0),link(1),link(2))),
+            IRNode::Output(0,link(3))
+        ));
+        let mut sim = Simulator::new(&module);
+        sim.set_input(0,2);
+        sim.set_input(1,-10);
+        // Wires don't wait for a tick.
+        assert_eq!(sim.get_output(0),-5);
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        let module = module(vec!(
+            IRNode::Input(0),
+            IRNode::BinOp(link(0),BinOp::Add,IRArg::Constant(1)),
+            IRNode::BinOp(link(0),BinOp::Mul,IRArg::Constant(2)),
+            IRNode::MultiDriver(vec!(link(0),link(0))),
+            IRNode::Output(0,link(1)),
+            IRNode::Output(1,link(2)),
+            IRNode::Output(2,link(3))
+        ));
+        let mut sim = Simulator::new(&module);
+        sim.set_input(0,i32::MAX);
+        sim.step();
+        assert_eq!(sim.get_output(0),i32::MIN);
+        assert_eq!(sim.get_output(1),-2);
+        assert_eq!(sim.get_output(2),-2);
+    }
+
+    #[test]
+    fn division_by_zero_is_zero() {
+        let module = module(vec!(
+            IRNode::Input(0),
+            IRNode::Input(1),
+            IRNode::BinOp(link(0),BinOp::Div,link(1)),
+            IRNode::BinOp(link(0),BinOp::Mod,link(1)),
+            IRNode::Output(0,link(2)),
+            IRNode::Output(1,link(3))
+        ));
+        let mut sim = Simulator::new(&module);
+        sim.set_input(0,7);
+        sim.set_input(1,2);
+        sim.step();
+        assert_eq!((sim.get_output(0),sim.get_output(1)),(3,1));
+        sim.set_input(1,0);
+        sim.step();
+        assert_eq!((sim.get_output(0),sim.get_output(1)),(0,0));
+    }
+
+    #[test]
+    fn gates_pass_their_input_or_zero() {
+        let module = module(vec!(
+            IRNode::Input(0),
+            IRNode::Input(1),
+            IRNode::BinOpCmpGate(link(0),BinOp::CmpGt,5,link(1)),
+            IRNode::Output(0,link(2))
+        ));
+        let mut sim = Simulator::new(&module);
+        sim.set_input(0,6);
+        sim.set_input(1,-9);
+        sim.step();
+        assert_eq!(sim.get_output(0),-9);
+        sim.set_input(0,5);
+        sim.step();
+        assert_eq!(sim.get_output(0),0);
+    }
+}
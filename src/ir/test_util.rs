@@ -0,0 +1,86 @@
+// Helpers for unit tests that build modules from source.
+
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::CompileSettings;
+use crate::assets;
+use crate::loader::{Loader, SourceArena};
+use crate::parser::ParseItem;
+use crate::symbols::SymbolTable;
+
+use super::{IRModule, ModuleTable, Namespace};
+
+/// The built-in signals.
+pub fn symbols() -> SymbolTable {
+    let mut table = SymbolTable::default();
+    table.add_json(&assets::get_asset_string("symbols.json").unwrap()).unwrap();
+    table
+}
+
+/// The modules declared in the prelude that can be built on their own.
+pub fn prelude_modules(symbols: &SymbolTable) -> Vec<String> {
+    let sources = SourceArena::default();
+    let mut loader = Loader::new(symbols,&sources);
+    let prelude = loader.load_embedded("std/prelude.cdl").unwrap();
+    loader.files[prelude].items.iter().filter_map(|item| match item {
+        ParseItem::Module(p_mod) if p_mod.params.is_empty() => Some(p_mod.name.to_owned()),
+        _ => None
+    }).collect()
+}
+
+/// Settings for building a module with every optimization enabled.
+pub fn settings(main_mod_name: &str) -> Rc<CompileSettings> {
+    Rc::new(CompileSettings{
+        fold_constants: true,
+        prune: true,
+        min_signals: false,
+        max_signals: None,
+        main_mod_name: main_mod_name.to_owned()
+    })
+}
+
+/// Builds a source file along with the prelude, and returns the module with the given name
+/// from the file, or from the prelude if the file has none.
+/// If `backend` is set, the module's colors, symbols and layout are also picked, as for the main module.
+/// Errors are returned as their message.
+fn build_module(symbols: &SymbolTable, source: &str, name: &str, backend: bool) -> Result<IRModule,String> {
+    let settings = settings(name);
+    let sources = SourceArena::default();
+    let mut loader = Loader::new(symbols,&sources);
+    let prelude = loader.load_embedded("std/prelude.cdl").unwrap();
+    let root = loader.load_disk(Path::new("test.cdl"),source.to_owned());
+    if !loader.errors.is_empty() {
+        return Err(loader.errors.join("\n"));
+    }
+
+    let mut modules = ModuleTable::default();
+    for (index,file) in loader.files.iter_mut().enumerate() {
+        let namespace = Namespace{
+            file: index,
+            imports: file.imports.clone(),
+            prelude: if index != prelude { Some(prelude) } else { None }
+        };
+        super::build_ir(std::mem::take(&mut file.items),settings.clone(),&namespace,&mut modules)
+            .map_err(|err| err.message)?;
+    }
+
+    let mut module = modules.take(root,name).or_else(|| modules.take(prelude,name))
+        .ok_or_else(|| format!("Module '{}' not found.",name))?;
+    if backend {
+        module.select_colors(&loader.files)?;
+        module.select_symbols(symbols,&loader.files)?;
+        module.layout_nodes();
+    }
+    Ok(module)
+}
+
+/// Builds a module, without picking colors or symbols.
+pub fn build(symbols: &SymbolTable, source: &str, name: &str) -> Result<IRModule,String> {
+    build_module(symbols,source,name,false)
+}
+
+/// Builds a module and runs the backend on it, so it is ready to be turned into a blueprint.
+pub fn compile(symbols: &SymbolTable, source: &str, name: &str) -> Result<IRModule,String> {
+    build_module(symbols,source,name,true)
+}
@@ -0,0 +1,177 @@
+// Runs `test` blocks in the simulator.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::common::UnaryOp;
+use crate::diagnostic::Span;
+use crate::loader::SourceFile;
+use crate::parser::{Expr, ItemPath, Test, TestStatement};
+
+use super::{IRModule, checked_narrow_constant};
+use super::scope::{ModuleTable, Namespace, Scope};
+use super::sim::Simulator;
+use super::vcd::VcdWriter;
+
+/// A test block, with the module it tests resolved.
+pub(super) struct TestCase<'a> {
+    pub test: Test<'a>,
+    pub namespace: Namespace,
+    pub module: ItemPath<'a>,
+    pub params: Vec<Expr<'a>>
+}
+
+pub struct TestResult {
+    /// The file the test was declared in.
+    pub file: usize,
+    pub name: String,
+    /// Why the test failed, and the span of the statement that failed.
+    pub failure: Option<(String,Span)>
+}
+
+impl<'a> ModuleTable<'a> {
+    /// Runs every test, in the order they were declared.
+    /// If `vcd_dir` is set, each test's simulation is written there as `<file stem>.<test name>.vcd`,
+    /// so tests with the same name in different files don't overwrite each other.
+    pub fn run_tests(&self, files: &[SourceFile], vcd_dir: Option<&Path>) -> Vec<TestResult> {
+        self.tests.iter().map(|case| {
+            let scope = Scope{modules: self, namespace: &case.namespace, locals: HashMap::new()};
+            let vcd_path = vcd_dir.map(|dir| {
+                let file_name = &files[case.namespace.file].name;
+                let stem = Path::new(file_name).file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+                dir.join(format!("{}.{}.vcd",stem,case.test.name))
+            });
+            let failure = scope.get_module(&case.module,&case.params)
+                .map_err(|err| (err,case.test.span))
+                .and_then(|module| run_test(&case.test,&module,&scope,vcd_path.as_deref()))
+                .err();
+            TestResult{file: case.namespace.file, name: case.test.name.to_owned(), failure}
+        }).collect()
+    }
+}
+
+fn run_test(test: &Test, module: &IRModule, scope: &Scope, vcd_path: Option<&Path>) -> Result<(),(String,Span)> {
+    let trace = vcd_path.map(|path| {
+        File::create(path)
+            .and_then(|file| VcdWriter::new(module,BufWriter::new(file)))
+            .map_err(|err| (format!("Failed to write '{}': {}",path.display(),err),test.span))
+    }).transpose()?;
+
+    let mut bench = TestBench{module, sim: Simulator::new(module), scope, trace};
+    let result = test.stmts.iter().try_for_each(|(stmt,span)| {
+        bench.run_stmt(test,stmt).map_err(|err| (err,*span))
+    });
+    // The trace is kept even when the test fails, since that is when it is most useful.
+    bench.record().map_err(|err| (err,test.span))?;
+    if let Some(trace) = bench.trace.take() {
+        trace.finish(&bench.sim).map_err(|err| (format!("Failed to write trace: {}",err),test.span))?;
+    }
+    result
+}
+
+fn narrow(num: i64) -> Result<i32,String> {
+    checked_narrow_constant(num).ok_or_else(|| format!("Constant {} does not fit in 32 bits.",num))
+}
+
+/// A module being simulated by a test.
+struct TestBench<'t,'m> {
+    module: &'m IRModule,
+    sim: Simulator<'m>,
+    scope: &'t Scope<'t,'t>,
+    trace: Option<VcdWriter<BufWriter<File>>>
+}
+
+impl<'t,'m> TestBench<'t,'m> {
+    /// Adds the current values to the trace, if there is one. Done once per tick, after inputs are driven.
+    fn record(&mut self) -> Result<(),String> {
+        if let Some(trace) = &mut self.trace {
+            trace.record(&self.sim).map_err(|err| format!("Failed to write trace: {}",err))?;
+        }
+        Ok(())
+    }
+
+    fn run_stmt(&mut self, test: &Test, stmt: &TestStatement) -> Result<(),String> {
+        match stmt {
+            TestStatement::Drive(input,expr) => {
+                let port = self.sim.find_input(&self.key(input.name,input.index.as_ref())?)?;
+                let value = self.eval(expr,&mut Vec::new())?;
+                self.sim.set_input(port,value);
+            },
+            TestStatement::Run(expr) => {
+                let ticks = self.eval(expr,&mut Vec::new())?;
+                if ticks < 0 {
+                    return Err(format!("Can not run for {} ticks.",ticks));
+                }
+                for _ in 0..ticks {
+                    self.record()?;
+                    self.sim.step();
+                }
+            },
+            TestStatement::Expect(expr) => {
+                let mut read = Vec::new();
+                if self.eval(expr,&mut read)? == 0 {
+                    let mut msg = format!("Test '{}' failed at tick {}.",test.name,self.sim.tick());
+                    if !read.is_empty() {
+                        let values: Vec<String> = read.iter().map(|(name,value)| format!("{} = {}",name,value)).collect();
+                        msg = format!("{} Values: {}.",msg,values.join(", "));
+                    }
+                    return Err(msg);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The key a name is stored under in the module's bindings, like `V[3]`.
+    fn key(&self, name: &str, index: Option<&Expr>) -> Result<String,String> {
+        if let Some(index) = index {
+            Ok(format!("{}[{}]",name,self.eval(index,&mut Vec::new())?))
+        } else {
+            Ok(name.to_owned())
+        }
+    }
+
+    /// Reads an input, binding, or output. Every value read is recorded, so failures can show them.
+    fn read(&self, name: &str, index: Option<&Expr>, read: &mut Vec<(String,i32)>) -> Result<i32,String> {
+        let key = self.key(name,index)?;
+        let value = self.sim.read(&key)?;
+        if !read.iter().any(|(other,_)| *other == key) {
+            read.push((key,value));
+        }
+        Ok(value)
+    }
+
+    /// Evaluates an expression with wrapping arithmetic, the same way a combinator would.
+    fn eval(&self, expr: &Expr, read: &mut Vec<(String,i32)>) -> Result<i32,String> {
+        match expr {
+            Expr::Constant(num) => narrow(*num),
+            // Names in the module shadow global constants.
+            Expr::Ident(name) => match self.scope.find_constant(name)? {
+                Some(num) if !self.module.bindings.contains_key(*name) && *name != "out" => narrow(num),
+                _ => self.read(name,None,read)
+            },
+            Expr::Index(name,index) => self.read(name,Some(index),read),
+            Expr::Path(_) => narrow(self.scope.eval_constant(expr)?),
+            Expr::UnOp(op,arg) => {
+                let arg = self.eval(arg,read)?;
+                Ok(match op {
+                    UnaryOp::Negate => arg.wrapping_neg(),
+                    UnaryOp::Plus => arg,
+                    UnaryOp::NotBitwise => !arg,
+                    UnaryOp::NotLogical => (arg == 0) as i32
+                })
+            },
+            Expr::BinOp(lhs,op,rhs) => Ok(op.fold(self.eval(lhs,read)?,self.eval(rhs,read)?)),
+            Expr::If(cond,val_true,Some(val_false)) => {
+                if self.eval(cond,read)? != 0 {
+                    self.eval(val_true,read)
+                } else {
+                    self.eval(val_false,read)
+                }
+            },
+            _ => Err(format!("Expression can not be used in a test: {:?}",expr))
+        }
+    }
+}
@@ -1,13 +1,28 @@
 use std::collections::HashMap;
 
 use crate::{blueprint::{ArithmeticConditions, Blueprint, Connection, ControlBehavior, DeciderConditions, Entity, Filter, Position, Signal}};
-use crate::symbols::signal_from_symbol_index;
+use crate::symbols::SymbolTable;
 
 use super::{IRArg, IRModule, IRNode, WireColor};
 use crate::common::ConnectType;
 
-struct BlueprintBuilder {
-    entities: Vec<Entity>
+struct BlueprintBuilder<'s> {
+    entities: Vec<Entity>,
+    symbols: &'s SymbolTable
+}
+
+/// Where a port of the module ended up in its blueprint.
+pub struct PortEntity {
+    pub entity_number: u32,
+    pub signal: Signal,
+    pub color: WireColor
+}
+
+/// The blueprint entities for the module's ports, in port order.
+#[derive(Default)]
+pub struct BlueprintPorts {
+    pub inputs: Vec<PortEntity>,
+    pub outputs: Vec<PortEntity>
 }
 
 fn make_pos(arg: (f32,f32)) -> Position {
@@ -32,24 +47,25 @@ enum SymbolOrConstant {
 }
 
 impl SymbolOrConstant {
-    fn unpack(&self) -> (Option<Signal>,Option<i32>) {
+    fn unpack(&self, symbols: &SymbolTable) -> (Option<Signal>,Option<i32>) {
         match self {
-            Self::Symbol(x) => (Some(signal_from_symbol_index(*x)),None),
+            Self::Symbol(x) => (Some(symbols.signal_from_symbol_index(*x)),None),
             Self::Constant(x) => (None,Some(*x)),
         }
     }
 }
 
-impl BlueprintBuilder{
-    fn new() -> Self {
+impl<'s> BlueprintBuilder<'s> {
+    fn new(symbols: &'s SymbolTable) -> Self {
         Self{
-            entities: Vec::new()
+            entities: Vec::new(),
+            symbols
         }
     }
 
     fn add_constant(&mut self, pos: (f32,f32), symbol: u32, count: i32) -> usize {
         let id = self.entities.len()+1;
-        let signal = signal_from_symbol_index(symbol);
+        let signal = self.symbols.signal_from_symbol_index(symbol);
         self.entities.push(Entity{
             entity_number: id as u32,
             name: "constant-combinator".to_owned(),
@@ -87,9 +103,9 @@ impl BlueprintBuilder{
     fn add_arithmetic(&mut self, pos: (f32,f32), operation: String, lhs: SymbolOrConstant, rhs: SymbolOrConstant, out_symbol: u32) -> usize {
         let id = self.entities.len()+1;
 
-        let (first_signal,first_constant) = lhs.unpack();
-        let (second_signal,second_constant) = rhs.unpack();
-        let output_signal = Some(signal_from_symbol_index(out_symbol));
+        let (first_signal,first_constant) = lhs.unpack(self.symbols);
+        let (second_signal,second_constant) = rhs.unpack(self.symbols);
+        let output_signal = Some(self.symbols.signal_from_symbol_index(out_symbol));
 
         self.entities.push(Entity{
             entity_number: id as u32,
@@ -117,9 +133,9 @@ impl BlueprintBuilder{
     fn add_decider(&mut self, pos: (f32,f32), comparator: String, lhs_symbol: u32, rhs: SymbolOrConstant, out_symbol: u32, copy_count_from_input: bool) -> usize {
         let id = self.entities.len()+1;
 
-        let first_signal = signal_from_symbol_index(lhs_symbol);
-        let (second_signal,constant) = rhs.unpack();
-        let output_signal = Some(signal_from_symbol_index(out_symbol));
+        let first_signal = Some(self.symbols.signal_from_symbol_index(lhs_symbol));
+        let (second_signal,constant) = rhs.unpack(self.symbols);
+        let output_signal = Some(self.symbols.signal_from_symbol_index(out_symbol));
 
         self.entities.push(Entity{
             entity_number: id as u32,
@@ -152,22 +168,22 @@ impl BlueprintBuilder{
         if color == WireColor::Red {
             self.entities[a.0-1].connections.as_mut().unwrap()
                 .entry(a_circuit_id).or_default()
-                .red.get_or_insert_with(|| Vec::new())
+                .red.get_or_insert_with(Vec::new)
                 .push(Connection{entity_id: b.0 as u32, circuit_id: Some(b_circuit_id)});
 
             self.entities[b.0-1].connections.as_mut().unwrap()
                 .entry(b_circuit_id).or_default()
-                .red.get_or_insert_with(|| Vec::new())
+                .red.get_or_insert_with(Vec::new)
                 .push(Connection{entity_id: a.0 as u32, circuit_id: Some(a_circuit_id)});
         } else if color == WireColor::Green {
             self.entities[a.0-1].connections.as_mut().unwrap()
                 .entry(a_circuit_id).or_default()
-                .green.get_or_insert_with(|| Vec::new())
+                .green.get_or_insert_with(Vec::new)
                 .push(Connection{entity_id: b.0 as u32, circuit_id: Some(b_circuit_id)});
 
             self.entities[b.0-1].connections.as_mut().unwrap()
                 .entry(b_circuit_id).or_default()
-                .green.get_or_insert_with(|| Vec::new())
+                .green.get_or_insert_with(Vec::new)
                 .push(Connection{entity_id: a.0 as u32, circuit_id: Some(a_circuit_id)});
         } else {
             panic!("no wire color in blueprint gen");
@@ -206,10 +222,10 @@ impl IRModule {
         }
     }
 
-    pub fn to_blueprint(&self) -> Blueprint {
-        let mut builder = BlueprintBuilder::new();
-        let mut ent_ids = Vec::new();
-        ent_ids.resize(self.nodes.len(), 0);
+    /// Builds the blueprint, and also reports which entities the ports were placed at.
+    pub fn to_blueprint_with_ports(&self, symbols: &SymbolTable) -> (Blueprint,BlueprintPorts) {
+        let mut builder = BlueprintBuilder::new(symbols);
+        let mut ent_ids = vec![0; self.nodes.len()];
         for (id, node) in self.nodes.iter().enumerate() {
             match node {
                 IRNode::Input(_) => {
@@ -292,6 +308,16 @@ impl IRModule {
             let (a_id,a_ty) = link.a.clone();
             let (b_id,b_ty) = link.b.clone();
 
+            // Ports must only be wired with their declared color, or they would not connect to the outside world.
+            for id in [a_id,b_id] {
+                if let Some(color) = self.port_color(id) {
+                    if color != link.color && color != WireColor::Both {
+                        panic!("Module '{}': Port ({}) is declared as {:?}, but was wired with {:?}.",
+                            self.name,self.nodes.get_debug(id as usize),color,link.color);
+                    }
+                }
+            }
+
             builder.add_link(link.color, 
                 (ent_ids[a_id as usize],a_ty),
                 (ent_ids[b_id as usize],b_ty)
@@ -320,6 +346,26 @@ impl IRModule {
             }
         }
 
-        builder.finish()
+        let mut ports = BlueprintPorts::default();
+        let mut port_list: Vec<_> = self.nodes.iter().enumerate().filter_map(|(id,node)| match node {
+            IRNode::Input(n) => Some((false,*n,id)),
+            IRNode::Output(n,_) => Some((true,*n,id)),
+            _ => None
+        }).collect();
+        port_list.sort();
+        for (is_output,_,id) in port_list {
+            let port = PortEntity{
+                entity_number: ent_ids[id] as u32,
+                signal: symbols.signal_from_symbol_index(self.out_symbols[id]),
+                color: self.port_color(id as u32).unwrap()
+            };
+            if is_output {
+                ports.outputs.push(port);
+            } else {
+                ports.inputs.push(port);
+            }
+        }
+
+        (builder.finish(),ports)
     }
 }
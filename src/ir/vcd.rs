@@ -0,0 +1,115 @@
+// Writes simulations as VCD waveforms, which can be opened in GTKWave.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use super::{IRModule, IRNode};
+use super::sim::Simulator;
+
+/// A traced value: the node it comes from and the short code VCD uses to refer to it.
+struct Var {
+    node: usize,
+    code: String,
+    last_value: Option<i32>
+}
+
+/// Records the ports and named bindings of a module as it is simulated.
+///
+/// Submodule instances become nested scopes. Bindings removed by optimization can not be traced,
+/// so `--no-opt` will keep more of them around.
+pub struct VcdWriter<W: Write> {
+    out: W,
+    vars: Vec<Var>,
+    /// The last tick with changes, or None if nothing has been written yet.
+    last_tick: Option<u32>
+}
+
+/// VCD identifier codes are made of printable ASCII characters.
+fn id_code(mut n: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return code;
+        }
+        n -= 1;
+    }
+}
+
+impl<W: Write> VcdWriter<W> {
+    /// Writes the header, which lists every traced value.
+    pub fn new(module: &IRModule, mut out: W) -> io::Result<Self> {
+        let output_count = module.nodes.iter().filter(|node| matches!(node, IRNode::Output(..))).count();
+
+        // Names are grouped by the submodule instances they came from.
+        let mut scopes: BTreeMap<Vec<&str>,Vec<(String,usize)>> = BTreeMap::new();
+        for (i,node) in module.nodes.iter().enumerate() {
+            let entry = match node {
+                IRNode::Removed => None,
+                // Outputs are named the same way tests read them.
+                IRNode::Output(..) if output_count == 1 => Some((Vec::new(),"out".to_owned())),
+                IRNode::Output(n,_) => Some((Vec::new(),format!("out[{}]",n))),
+                _ => module.nodes.binding_name(i).map(|(instances,name)| {
+                    (instances.iter().map(String::as_str).collect(),name.to_owned())
+                })
+            };
+            if let Some((instances,name)) = entry {
+                scopes.entry(instances).or_default().push((name,i));
+            }
+        }
+
+        writeln!(out,"$version combinatorio $end")?;
+        writeln!(out,"$comment Each time step is one game tick. $end")?;
+        writeln!(out,"$scope module {} $end",module.name)?;
+        let mut vars = Vec::new();
+        let mut current: Vec<&str> = Vec::new();
+        for (path,names) in &scopes {
+            let common = current.iter().zip(path).take_while(|(a,b)| a == b).count();
+            for _ in common..current.len() {
+                writeln!(out,"$upscope $end")?;
+            }
+            for instance in &path[common..] {
+                writeln!(out,"$scope module {} $end",instance)?;
+            }
+            current = path.clone();
+
+            for (name,node) in names {
+                let code = id_code(vars.len());
+                writeln!(out,"$var integer 32 {} {} $end",code,name)?;
+                vars.push(Var{node: *node, code, last_value: None});
+            }
+        }
+        for _ in 0..current.len() + 1 {
+            writeln!(out,"$upscope $end")?;
+        }
+        writeln!(out,"$enddefinitions $end")?;
+
+        Ok(VcdWriter{out, vars, last_tick: None})
+    }
+
+    /// Writes every value that changed since the last record.
+    pub fn record(&mut self, sim: &Simulator) -> io::Result<()> {
+        for var in &mut self.vars {
+            let value = sim.get_node(var.node);
+            if var.last_value != Some(value) {
+                // Ticks where nothing changed are left out.
+                if self.last_tick != Some(sim.tick()) {
+                    writeln!(self.out,"#{}",sim.tick())?;
+                    self.last_tick = Some(sim.tick());
+                }
+                writeln!(self.out,"b{:b} {}",value as u32,var.code)?;
+                var.last_value = Some(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the final tick, so viewers show how long the last values were held.
+    pub fn finish(mut self, sim: &Simulator) -> io::Result<()> {
+        if self.last_tick != Some(sim.tick()) {
+            writeln!(self.out,"#{}",sim.tick())?;
+        }
+        self.out.flush()
+    }
+}
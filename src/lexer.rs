@@ -1,16 +1,26 @@
 use std::str::FromStr;
+use std::fmt;
+
+use crate::diagnostic::{Diagnostic, Span};
 
 #[derive(Debug,PartialEq,Clone,Copy)]
 pub enum LexToken<'a> {
     Ident(&'a str),
     Symbol(&'a str),
     Number(i64),
+    Str(&'a str),
 
     KeyMod,
     KeyOutput,
     KeyLet,
     KeyConst,
     KeyMatch,
+    KeyUse,
+    KeyPub,
+    KeyAs,
+    KeyFor,
+    KeyIn,
+    KeyEnum,
 
     OpAdd,
     OpSub,
@@ -38,7 +48,10 @@ pub enum LexToken<'a> {
     OpMatchArrow,
     OpThinArrow,
     OpColon,
+    OpPath,
     OpQuestion,
+    OpRange,
+    OpAt,
 
     OpNotBitwise,
     OpNotLogical,
@@ -47,6 +60,15 @@ pub enum LexToken<'a> {
     OpParenClose,
     OpBraceOpen,
     OpBraceClose,
+    OpBracketOpen,
+    OpBracketClose,
+}
+
+/// A token along with the location it was lexed from.
+#[derive(Debug,Clone,Copy)]
+pub struct Token<'a> {
+    pub kind: LexToken<'a>,
+    pub span: Span
 }
 
 impl<'a> LexToken<'a> {
@@ -57,219 +79,392 @@ impl<'a> LexToken<'a> {
             "let" => Self::KeyLet,
             "match" => Self::KeyMatch,
             "const" => Self::KeyConst,
-            "use" => panic!("'{}' is a keyword reserved for future use.",ident),
+            "use" => Self::KeyUse,
+            "pub" => Self::KeyPub,
+            "as" => Self::KeyAs,
+            "for" => Self::KeyFor,
+            "in" => Self::KeyIn,
+            "enum" => Self::KeyEnum,
             _ => Self::Ident(ident)
         }
     }
 
     pub fn is_compare_op(&self) -> bool {
-        match self {
+        matches!(self,
             Self::OpCmpEq |
             Self::OpCmpNeq |
             Self::OpCmpGt |
             Self::OpCmpLt |
             Self::OpCmpGeq |
-            Self::OpCmpLeq => true,
-            _ => false
-        }
+            Self::OpCmpLeq
+        )
+    }
+}
+
+impl<'a> fmt::Display for LexToken<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Self::Ident(id) => return write!(f,"identifier `{}`",id),
+            Self::Symbol(sym) => return write!(f,"symbol `${}`",sym),
+            Self::Number(n) => return write!(f,"number `{}`",n),
+            Self::Str(string) => return write!(f,"string \"{}\"",string),
+
+            Self::KeyMod => "mod",
+            Self::KeyOutput => "output",
+            Self::KeyLet => "let",
+            Self::KeyConst => "const",
+            Self::KeyMatch => "match",
+            Self::KeyUse => "use",
+            Self::KeyPub => "pub",
+            Self::KeyAs => "as",
+            Self::KeyFor => "for",
+            Self::KeyIn => "in",
+            Self::KeyEnum => "enum",
+
+            Self::OpAdd => "+",
+            Self::OpSub => "-",
+            Self::OpMul => "*",
+            Self::OpDiv => "/",
+            Self::OpMod => "%",
+            Self::OpPower => "**",
+
+            Self::OpBitOr => "|",
+            Self::OpBitAnd => "&",
+            Self::OpBitXor => "^",
+            Self::OpShiftLeft => "<<",
+            Self::OpShiftRight => ">>",
+
+            Self::OpCmpEq => "==",
+            Self::OpCmpNeq => "!=",
+            Self::OpCmpGt => ">",
+            Self::OpCmpLt => "<",
+            Self::OpCmpGeq => ">=",
+            Self::OpCmpLeq => "<=",
+
+            Self::OpAssign => "=",
+            Self::OpComma => ",",
+            Self::OpSemicolon => ";",
+            Self::OpMatchArrow => "=>",
+            Self::OpThinArrow => "->",
+            Self::OpColon => ":",
+            Self::OpPath => "::",
+            Self::OpQuestion => "?",
+            Self::OpAt => "@",
+            Self::OpRange => "..",
+
+            Self::OpNotBitwise => "~",
+            Self::OpNotLogical => "!",
+
+            Self::OpParenOpen => "(",
+            Self::OpParenClose => ")",
+            Self::OpBraceOpen => "{",
+            Self::OpBraceClose => "}",
+            Self::OpBracketOpen => "[",
+            Self::OpBracketClose => "]",
+        };
+        write!(f,"`{}`",text)
     }
 }
 
 pub struct Lexer<'a> {
+    source: &'a str,
     chars: std::str::Chars<'a>
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(string: &str) -> Lexer {
+    pub fn new(source: &'a str) -> Self {
         Lexer{
-            chars: string.chars()
+            source,
+            chars: source.chars()
         }
     }
+
+    /// Byte offset of the next character to be lexed.
+    pub fn offset(&self) -> usize {
+        self.source.len() - self.chars.as_str().len()
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = LexToken<'a>;
+    type Item = Result<Token<'a>,Diagnostic>;
     
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let parse_str = self.chars.as_str();
-            
-            return if let Some(c) = self.chars.next() {
-                if c.is_ascii_alphabetic() || c == '_' {
-                    let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
-                        .unwrap_or(parse_str.len());
-                    let token_str = &parse_str[0..token_end];
-                    let remainder_str = &parse_str[token_end..];
-    
-                    self.chars = remainder_str.chars();
-    
-                    Some(LexToken::ident_or_keyword(token_str))
-                } else if c.is_ascii_digit() {
-                    // TODO _ seperators?
-                    // Don't bother handling negatives. All constants are unsigned.
-
-                    let next_char = parse_str.chars().nth(1);
-
-                    if c == '0' && next_char.unwrap_or('0').is_ascii_alphabetic() {
-                        let radix = if next_char == Some('x') {
-                            16
-                        } else if next_char == Some('b') {
-                            2
-                        } else {
-                            panic!("Bad radix indicator '{:?}'.",next_char);
-                        };
+            let start = self.offset();
+            let result = self.next_kind()?;
+            let span = Span::new(start,self.offset());
 
-                        let parse_str = &parse_str[2..];
-                        
-                        let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric())
-                            .unwrap_or(parse_str.len());
-                        let token_str = &parse_str[0..token_end];
-                        let remainder_str = &parse_str[token_end..];
-    
-                        self.chars = remainder_str.chars();
+            return match result {
+                Ok(Some(kind)) => Some(Ok(Token{kind,span})),
+                Ok(None) => continue,
+                Err(msg) => Some(Err(Diagnostic::new(msg,span)))
+            };
+        }
+    }
+}
 
-                        let num = i64::from_str_radix(token_str, radix).expect("failed to parse int");
-    
-                        Some(LexToken::Number(num))
+impl<'a> Lexer<'a> {
+    /// Lexes a single token. Returns Ok(None) for skipped input such as whitespace and comments.
+    fn next_kind(&mut self) -> Option<Result<Option<LexToken<'a>>,String>> {
+        let parse_str = self.chars.as_str();
+        
+        if let Some(c) = self.chars.next() {
+            if c.is_ascii_alphabetic() || c == '_' {
+                let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(parse_str.len());
+                let token_str = &parse_str[0..token_end];
+                let remainder_str = &parse_str[token_end..];
+
+                self.chars = remainder_str.chars();
+
+                Some(Ok(Some(LexToken::ident_or_keyword(token_str))))
+            } else if c.is_ascii_digit() {
+                // TODO _ seperators?
+                // Don't bother handling negatives. All constants are unsigned.
+
+                let next_char = parse_str.chars().nth(1);
+
+                if c == '0' && next_char.unwrap_or('0').is_ascii_alphabetic() {
+                    let radix = if next_char == Some('x') {
+                        16
+                    } else if next_char == Some('b') {
+                        2
                     } else {
-                        let token_end = parse_str.find(|c: char| !c.is_ascii_digit())
+                        let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric())
                             .unwrap_or(parse_str.len());
-                        let token_str = &parse_str[0..token_end];
-                        let remainder_str = &parse_str[token_end..];
-    
-                        self.chars = remainder_str.chars();
-    
-                        let num = i64::from_str(token_str).expect("failed to parse int");
-    
-                        Some(LexToken::Number(num))
-                    }
-                } else if c.is_ascii_whitespace() {
-                    // TODO line handling?
-                    // Probably use another iterator wrapper if we want that?
-                    // skip
-                    continue;
-                } else if c == '$' {
-                    let parse_str = self.chars.as_str();
-
-                    let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        self.chars = parse_str[token_end..].chars();
+                        return Some(Err(format!("Bad radix indicator `{}`.",next_char.unwrap())));
+                    };
+
+                    let parse_str = &parse_str[2..];
+                    
+                    let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric())
                         .unwrap_or(parse_str.len());
                     let token_str = &parse_str[0..token_end];
                     let remainder_str = &parse_str[token_end..];
 
                     self.chars = remainder_str.chars();
 
-                    Some(LexToken::Symbol(token_str))
+                    Some(i64::from_str_radix(token_str, radix)
+                        .map(|num| Some(LexToken::Number(num)))
+                        .map_err(|_| format!("Invalid base-{} number literal.",radix)))
                 } else {
-                    match c {
-                        '+' => Some(LexToken::OpAdd),
-                        '-' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('>') {
-                                self.chars.next();
-                                Some(LexToken::OpThinArrow)
-                            } else {
-                                Some(LexToken::OpSub)
-                            }
-                        },
-                        '%' => Some(LexToken::OpMod),
-                        '/' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('/') {
-                                // Single-line comment.
-                                while self.chars.next() != Some('\n') { }
-                                continue;
-                            } else if next_char == Some('*') {
-                                self.chars.next();
-                                while !self.chars.as_str().starts_with("*/") {
-                                    self.chars.next();
-                                }
-                                self.chars.next();
-                                self.chars.next();
-                                continue;
-                            } else {
-                                Some(LexToken::OpDiv)
-                            }
-                        },
-                        '*' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('*') {
-                                self.chars.next();
-                                Some(LexToken::OpPower)
-                            } else {
-                                Some(LexToken::OpMul)
-                            }
-                        },
-
-                        '|' => Some(LexToken::OpBitOr),
-                        '&' => Some(LexToken::OpBitAnd),
-                        '^' => Some(LexToken::OpBitXor),
-                        '~' => Some(LexToken::OpNotBitwise),
-
-                        '=' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('=') {
-                                self.chars.next();
-                                Some(LexToken::OpCmpEq)
-                            } else if next_char == Some('>') {
-                                self.chars.next();
-                                Some(LexToken::OpMatchArrow)
-                            } else {
-                                Some(LexToken::OpAssign)
-                            }
-                        },
-
-                        '!' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('=') {
-                                self.chars.next();
-                                Some(LexToken::OpCmpNeq)
-                            } else {
-                                Some(LexToken::OpNotLogical)
-                            }
-                        },
-
-                        '<' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('=') {
-                                self.chars.next();
-                                Some(LexToken::OpCmpLeq)
-                            } else if next_char == Some('<') {
-                                self.chars.next();
-                                Some(LexToken::OpShiftLeft)
-                            } else {
-                                Some(LexToken::OpCmpLt)
-                            }
-                        },
-
-                        '>' => {
-                            let next_char = parse_str.chars().nth(1);
-                            if next_char == Some('=') {
-                                self.chars.next();
-                                Some(LexToken::OpCmpGeq)
-                            } else if next_char == Some('>') {
-                                self.chars.next();
-                                Some(LexToken::OpShiftRight)
-                            } else {
-                                Some(LexToken::OpCmpGt)
-                            }
-                        },
+                    let token_end = parse_str.find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(parse_str.len());
+                    let token_str = &parse_str[0..token_end];
+                    let remainder_str = &parse_str[token_end..];
 
-                        '?' => Some(LexToken::OpQuestion),
-                        ':' => Some(LexToken::OpColon),
-                        ',' => Some(LexToken::OpComma),
-                        ';' => Some(LexToken::OpSemicolon),
+                    self.chars = remainder_str.chars();
 
-                        '(' => Some(LexToken::OpParenOpen),
-                        ')' => Some(LexToken::OpParenClose),
-                        '{' => Some(LexToken::OpBraceOpen),
-                        '}' => Some(LexToken::OpBraceClose),
+                    Some(i64::from_str(token_str)
+                        .map(|num| Some(LexToken::Number(num)))
+                        .map_err(|_| format!("Invalid number literal `{}`.",token_str)))
+                }
+            } else if c.is_ascii_whitespace() {
+                // skip
+                Some(Ok(None))
+            } else if c == '"' {
+                // No escapes. Strings are only used for file paths.
+                let parse_str = self.chars.as_str();
 
-                        _ => panic!("unhandled char [{}]",c)
+                if let Some(token_end) = parse_str.find(['"','\n']) {
+                    if &parse_str[token_end..token_end+1] == "\"" {
+                        self.chars = parse_str[token_end+1..].chars();
+                        return Some(Ok(Some(LexToken::Str(&parse_str[0..token_end]))));
                     }
+                    self.chars = parse_str[token_end..].chars();
+                } else {
+                    self.chars = "".chars();
                 }
+                Some(Err("Unterminated string.".to_owned()))
+            } else if c == '$' {
+                let parse_str = self.chars.as_str();
+
+                let token_end = parse_str.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(parse_str.len());
+                let token_str = &parse_str[0..token_end];
+                let remainder_str = &parse_str[token_end..];
+
+                self.chars = remainder_str.chars();
+
+                if token_str.is_empty() {
+                    return Some(Err("Expected a signal name after `$`.".to_owned()));
+                }
+                Some(Ok(Some(LexToken::Symbol(token_str))))
             } else {
-                None
+                match c {
+                    '+' => Some(LexToken::OpAdd),
+                    '-' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('>') {
+                            self.chars.next();
+                            Some(LexToken::OpThinArrow)
+                        } else {
+                            Some(LexToken::OpSub)
+                        }
+                    },
+                    '%' => Some(LexToken::OpMod),
+                    '/' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('/') {
+                            // Single-line comment.
+                            while !matches!(self.chars.next(), Some('\n') | None) { }
+                            return Some(Ok(None));
+                        } else if next_char == Some('*') {
+                            self.chars.next();
+                            while !self.chars.as_str().starts_with("*/") {
+                                if self.chars.next().is_none() {
+                                    return Some(Err("Unterminated block comment.".to_owned()));
+                                }
+                            }
+                            self.chars.next();
+                            self.chars.next();
+                            return Some(Ok(None));
+                        } else {
+                            Some(LexToken::OpDiv)
+                        }
+                    },
+                    '*' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('*') {
+                            self.chars.next();
+                            Some(LexToken::OpPower)
+                        } else {
+                            Some(LexToken::OpMul)
+                        }
+                    },
+
+                    '|' => Some(LexToken::OpBitOr),
+                    '&' => Some(LexToken::OpBitAnd),
+                    '^' => Some(LexToken::OpBitXor),
+                    '~' => Some(LexToken::OpNotBitwise),
+
+                    '=' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('=') {
+                            self.chars.next();
+                            Some(LexToken::OpCmpEq)
+                        } else if next_char == Some('>') {
+                            self.chars.next();
+                            Some(LexToken::OpMatchArrow)
+                        } else {
+                            Some(LexToken::OpAssign)
+                        }
+                    },
+
+                    '!' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('=') {
+                            self.chars.next();
+                            Some(LexToken::OpCmpNeq)
+                        } else {
+                            Some(LexToken::OpNotLogical)
+                        }
+                    },
+
+                    '<' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('=') {
+                            self.chars.next();
+                            Some(LexToken::OpCmpLeq)
+                        } else if next_char == Some('<') {
+                            self.chars.next();
+                            Some(LexToken::OpShiftLeft)
+                        } else {
+                            Some(LexToken::OpCmpLt)
+                        }
+                    },
+
+                    '>' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('=') {
+                            self.chars.next();
+                            Some(LexToken::OpCmpGeq)
+                        } else if next_char == Some('>') {
+                            self.chars.next();
+                            Some(LexToken::OpShiftRight)
+                        } else {
+                            Some(LexToken::OpCmpGt)
+                        }
+                    },
+
+                    '?' => Some(LexToken::OpQuestion),
+                    '@' => Some(LexToken::OpAt),
+                    ':' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some(':') {
+                            self.chars.next();
+                            Some(LexToken::OpPath)
+                        } else {
+                            Some(LexToken::OpColon)
+                        }
+                    },
+                    '.' => {
+                        let next_char = parse_str.chars().nth(1);
+                        if next_char == Some('.') {
+                            self.chars.next();
+                            Some(LexToken::OpRange)
+                        } else {
+                            return Some(Err("Unexpected character `.`.".to_owned()));
+                        }
+                    },
+                    ',' => Some(LexToken::OpComma),
+                    ';' => Some(LexToken::OpSemicolon),
+
+                    '(' => Some(LexToken::OpParenOpen),
+                    ')' => Some(LexToken::OpParenClose),
+                    '{' => Some(LexToken::OpBraceOpen),
+                    '}' => Some(LexToken::OpBraceClose),
+                    '[' => Some(LexToken::OpBracketOpen),
+                    ']' => Some(LexToken::OpBracketClose),
+
+                    _ => return Some(Err(format!("Unexpected character `{}`.",c)))
+                }.map(|tok| Ok(Some(tok)))
             }
+        } else {
+            None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<(LexToken<'_>,Span)> {
+        Lexer::new(source).map(|tok| {
+            let tok = tok.unwrap();
+            (tok.kind,tok.span)
+        }).collect()
+    }
+
+    #[test]
+    fn token_spans() {
+        let source = "let x = 0x1F; // comment\n  y[i]";
+        let tokens = lex(source);
+        let texts: Vec<&str> = tokens.iter().map(|(_,span)| &source[span.start..span.end]).collect();
+        assert_eq!(texts,["let","x","=","0x1F",";","y","[","i","]"]);
+        assert_eq!(tokens[3],(LexToken::Number(0x1F),Span::new(8,12)));
+        assert_eq!(tokens[5],(LexToken::Ident("y"),Span::new(27,28)));
+    }
+
+    #[test]
+    fn spans_are_byte_offsets() {
+        // The comment has a multi-byte character, so char and byte offsets differ after it.
+        let tokens = lex("// é\n$A");
+        assert_eq!(tokens,[(LexToken::Symbol("A"),Span::new(6,8))]);
+    }
+
+    #[test]
+    fn error_spans() {
+        let results: Vec<_> = Lexer::new("a 0q1 b").collect();
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(err.span,Span::new(2,5));
+        // Lexing continues after the error.
+        assert_eq!(results[2].as_ref().unwrap().span,Span::new(6,7));
+    }
+}
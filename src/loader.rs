@@ -0,0 +1,271 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::assets;
+use crate::diagnostic::Diagnostic;
+use crate::parser::{self, ParseItem};
+use crate::symbols::SymbolTable;
+
+/// Identifies a file so it is only loaded once.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+enum FileKey {
+    Disk(PathBuf),
+    Embedded(String)
+}
+
+/// Owns the text of every loaded file, so parsed items can borrow from it for as long as the arena lives.
+#[derive(Default)]
+pub struct SourceArena {
+    texts: RefCell<Vec<String>>
+}
+
+impl SourceArena {
+    fn alloc(&self, text: String) -> &str {
+        let mut texts = self.texts.borrow_mut();
+        texts.push(text);
+        let text: &str = texts.last().unwrap();
+        // SAFETY: Texts are never removed or modified, and a string's buffer does not move when the
+        // vector holding it grows, so the text lives as long as the arena.
+        unsafe { &*(text as *const str) }
+    }
+}
+
+pub struct SourceFile<'a> {
+    /// The path shown in errors.
+    pub name: String,
+    pub text: &'a str,
+    /// The file's parsed items. Empty if the file had syntax errors.
+    pub items: Vec<ParseItem<'a>>,
+    /// Files this file imports, by the name used to qualify calls into them.
+    pub imports: Vec<(String,usize)>
+}
+
+/// Loads a source file along with everything it imports.
+///
+/// Files are stored in dependency order: every file comes after the files it imports,
+/// so modules can be built front to back.
+pub struct Loader<'a> {
+    symbols: &'a SymbolTable,
+    sources: &'a SourceArena,
+    pub files: Vec<SourceFile<'a>>,
+    loaded: HashMap<FileKey,usize>,
+    /// Files that are currently being loaded, used to detect import cycles.
+    stack: Vec<(FileKey,String)>,
+    /// Rendered errors from every file that was loaded.
+    pub errors: Vec<String>
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(symbols: &'a SymbolTable, sources: &'a SourceArena) -> Self {
+        Loader{symbols, sources, files: Vec::new(), loaded: HashMap::new(), stack: Vec::new(), errors: Vec::new()}
+    }
+
+    /// Loads a file from the embedded assets, such as the prelude.
+    pub fn load_embedded(&mut self, asset_path: &str) -> Option<usize> {
+        let text = assets::get_asset_string(asset_path)?;
+        Some(self.load(FileKey::Embedded(asset_path.to_owned()),asset_path.to_owned(),text))
+    }
+
+    /// Loads a file from disk. The text is passed in so callers can generate sources, like ROMs.
+    pub fn load_disk(&mut self, path: &Path, text: String) -> usize {
+        let key = FileKey::Disk(path.canonicalize().unwrap_or_else(|_| path.to_owned()));
+        self.load(key,path.display().to_string(),text)
+    }
+
+    fn load(&mut self, key: FileKey, name: String, text: String) -> usize {
+        if let Some(index) = self.loaded.get(&key) {
+            return *index;
+        }
+
+        let text = self.sources.alloc(text);
+        let mut imports: Vec<(String,usize)> = Vec::new();
+        let items = match parser::parse(text,self.symbols) {
+            Ok(items) => {
+                self.stack.push((key.clone(),name.clone()));
+                for item in &items {
+                    if let ParseItem::Use(path,alias,span) = *item {
+                        let res = import_alias(path,alias).and_then(|alias| {
+                            if imports.iter().any(|(other,_)| *other == alias) {
+                                return Err(format!("The name `{}` is imported more than once. Use `as` to rename one of the imports.",alias));
+                            }
+                            self.load_import(&key,path).map(|index| (alias,index))
+                        });
+                        match res {
+                            Ok(import) => imports.push(import),
+                            Err(msg) => {
+                                let err = Diagnostic::new(msg,span);
+                                self.errors.push(err.render(&name,text));
+                            }
+                        }
+                    }
+                }
+                self.stack.pop();
+                items
+            },
+            Err(errors) => {
+                for err in errors {
+                    self.errors.push(err.render(&name,text));
+                }
+                Vec::new()
+            }
+        };
+
+        let index = self.files.len();
+        self.files.push(SourceFile{name,text,items,imports});
+        self.loaded.insert(key,index);
+        index
+    }
+
+    fn load_import(&mut self, importer: &FileKey, path: &str) -> Result<usize,String> {
+        let file_path = if path.ends_with(".cdl") {
+            path.to_owned()
+        } else {
+            format!("{}.cdl",path)
+        };
+
+        let (key,name,text) = self.resolve(importer,&file_path)
+            .ok_or_else(|| format!("Cannot find file \"{}\".",path))?;
+
+        if let Some(pos) = self.stack.iter().position(|(k,_)| *k == key) {
+            let mut chain: Vec<&str> = self.stack[pos..].iter().map(|(_,name)| name.as_str()).collect();
+            chain.push(&self.stack[pos].1);
+            return Err(format!("Import cycle detected: {}.",chain.join(" -> ")));
+        }
+
+        Ok(self.load(key,name,text))
+    }
+
+    /// Finds an imported file, first relative to the importing file, then in the embedded assets.
+    fn resolve(&self, importer: &FileKey, file_path: &str) -> Option<(FileKey,String,String)> {
+        match importer {
+            FileKey::Disk(importer_path) => {
+                let dir = importer_path.parent().unwrap_or_else(|| Path::new("."));
+                let candidate = dir.join(file_path);
+                if let Ok(text) = std::fs::read_to_string(&candidate) {
+                    let key = FileKey::Disk(candidate.canonicalize().unwrap_or_else(|_| candidate.clone()));
+                    return Some((key,display_path(&candidate),text));
+                }
+            },
+            FileKey::Embedded(importer_path) => {
+                if let Some((dir,_)) = importer_path.rsplit_once('/') {
+                    let candidate = normalize_asset_path(&format!("{}/{}",dir,file_path));
+                    if let Some(text) = assets::get_asset_string(&candidate) {
+                        return Some((FileKey::Embedded(candidate.clone()),candidate,text));
+                    }
+                }
+            }
+        }
+
+        let candidate = normalize_asset_path(file_path);
+        assets::get_asset_string(&candidate).map(|text| (FileKey::Embedded(candidate.clone()),candidate,text))
+    }
+}
+
+/// By default, an import is named after its file, so `use "std/math";` allows calls like `math::foo()`.
+fn import_alias(path: &str, alias: Option<&str>) -> Result<String,String> {
+    if let Some(alias) = alias {
+        return Ok(alias.to_owned());
+    }
+    let stem = path.rsplit('/').next().unwrap_or(path);
+    let stem = stem.strip_suffix(".cdl").unwrap_or(stem);
+
+    let is_ident = stem.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+        stem.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_ident {
+        Ok(stem.to_owned())
+    } else {
+        Err(format!("`{}` is not a valid namespace name. Use `as` to name the import.",stem))
+    }
+}
+
+/// Shows disk paths relative to the working directory where possible, to keep errors short.
+fn display_path(path: &Path) -> String {
+    std::env::current_dir().ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(|p| p.display().to_string()))
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// Resolves `.` and `..` components, since embedded assets are looked up by exact name.
+fn normalize_asset_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            },
+            _ => parts.push(part)
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes files to a fresh directory, so tests can load real imports.
+    fn write_files(test_name: &str, files: &[(&str,&str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("combinatorio-{}-{}",test_name,std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name,text) in files {
+            std::fs::write(dir.join(name),text).unwrap();
+        }
+        dir
+    }
+
+    fn load<'a>(symbols: &'a SymbolTable, sources: &'a SourceArena, dir: &Path, name: &str) -> Loader<'a> {
+        let mut loader = Loader::new(symbols,sources);
+        let path = dir.join(name);
+        let text = std::fs::read_to_string(&path).unwrap();
+        loader.load_disk(&path,text);
+        loader
+    }
+
+    #[test]
+    fn import_cycle() {
+        let dir = write_files("cycle",&[
+            ("a.cdl","use \"b\";\nmod main(x) { output(x); }"),
+            ("b.cdl","use \"c\";"),
+            ("c.cdl","use \"b\";")
+        ]);
+        let symbols = SymbolTable::default();
+        let sources = SourceArena::default();
+        let loader = load(&symbols,&sources,&dir,"a.cdl");
+        assert_eq!(loader.errors.len(),1);
+        assert!(loader.errors[0].contains("Import cycle detected: "));
+        assert!(loader.errors[0].contains("b.cdl -> ") && loader.errors[0].contains("c.cdl -> "));
+        // Every file is still loaded once, in dependency order.
+        let names: Vec<&str> = loader.files.iter().map(|file| file.name.rsplit('/').next().unwrap()).collect();
+        assert_eq!(names,["c.cdl","b.cdl","a.cdl"]);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn self_import() {
+        let dir = write_files("self",&[("a.cdl","use \"a\";")]);
+        let symbols = SymbolTable::default();
+        let sources = SourceArena::default();
+        let loader = load(&symbols,&sources,&dir,"a.cdl");
+        assert_eq!(loader.errors.len(),1);
+        assert!(loader.errors[0].contains("Import cycle detected: "));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn shared_import_is_not_a_cycle() {
+        let dir = write_files("diamond",&[
+            ("a.cdl","use \"b\";\nuse \"c\";"),
+            ("b.cdl","use \"d\";"),
+            ("c.cdl","use \"d\";"),
+            ("d.cdl","")
+        ]);
+        let symbols = SymbolTable::default();
+        let sources = SourceArena::default();
+        let loader = load(&symbols,&sources,&dir,"a.cdl");
+        assert!(loader.errors.is_empty(),"{:?}",loader.errors);
+        assert_eq!(loader.files.len(),4);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
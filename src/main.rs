@@ -1,98 +1,349 @@
-use std::{collections::HashMap, rc::Rc};
+use std::path::Path;
 
-use clap::{Parser as CmdParser};
+use clap::{AppSettings, Parser as CmdParser, Subcommand};
+use rand::Rng;
 
 mod common;
+mod diagnostic;
 
 mod lexer;
 mod parser;
 mod ir;
 mod blueprint;
+mod blueprint_sim;
 mod disjoint_set;
 mod symbols;
 mod assets;
+mod loader;
+mod session;
 
 mod rom_generator;
 
 #[derive(CmdParser)]
 #[clap(version = "1.0.0", author = "cogg <adam@cogg.rocks>")]
+#[clap(setting = AppSettings::SubcommandsNegateReqs, setting = AppSettings::ArgsNegateSubcommands)]
 struct CmdOptions {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(required = true)]
     /// The source file to compile.
-    filename: String,
+    filename: Option<String>,
     #[clap(default_value = "main")]
     /// The name of the top-level module to generate a blueprint for.
     mod_name: String,
 
-    #[clap(long)]
+    #[clap(long, global = true)]
     /// Disable all optimizations.
     no_opt: bool,
-    #[clap(long)]
+    #[clap(long, global = true)]
     /// Disable constant folding.
     no_fold: bool,
-    #[clap(long)]
+    #[clap(long, global = true)]
     /// Disable pruning unused combinators.
     no_prune: bool,
 
     #[clap(long)]
-    rom_offset: Option<u32>
+    /// Try to use as few distinct signals as possible.
+    min_signals: bool,
+    #[clap(long)]
+    /// Fail if the design needs more than this many distinct signals. Implies --min-signals.
+    max_signals: Option<u32>,
+
+    #[clap(long)]
+    /// Simulate the module and its blueprint side by side for this many ticks, with random inputs,
+    /// and fail if their outputs ever differ.
+    check_sim: Option<u32>,
+
+    #[clap(long, global = true)]
+    rom_offset: Option<u32>,
+    #[clap(long, global = true)]
+    /// A JSON file of extra signal definitions. These are added to the built-in signals and
+    /// override any with the same id. A `symbols.json` next to the source file is loaded first.
+    symbols: Option<String>,
+    #[clap(long, global = true, default_value = "virtual")]
+    /// Comma-separated signal categories (virtual, item, fluid) that may be used for values without a declared signal.
+    auto_signals: String
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the tests in a source file, the files it imports, and the prelude.
+    Test {
+        /// The source file to test. If this is left out, only the prelude is tested.
+        filename: Option<String>,
+        /// Write a VCD trace of each test to this directory, named after the test and its file.
+        #[clap(long)]
+        vcd: Option<String>
+    },
+    /// Simulate a module, stepping it from an interactive prompt.
+    Repl {
+        /// The source file containing the module.
+        filename: String,
+        #[clap(default_value = "main")]
+        /// The module to simulate.
+        mod_name: String
+    }
 }
 
 #[derive(Debug)]
 pub struct CompileSettings {
     fold_constants: bool,
     prune: bool,
+    min_signals: bool,
+    max_signals: Option<u32>,
     main_mod_name: String
 }
 
+fn read_or_exit<T>(res: std::io::Result<T>, file_name: &str) -> T {
+    res.unwrap_or_else(|err| {
+        eprintln!("error: failed to read '{}': {}",file_name,err);
+        std::process::exit(1);
+    })
+}
+
+/// Loads the built-in signals, then the project's symbol file, then the one given on the command line.
+/// Later files override earlier ones.
+fn load_symbols(options: &CmdOptions, filename: Option<&str>) -> symbols::SymbolTable {
+    let mut table = symbols::SymbolTable::default();
+    let mut sources = vec!(("symbols.json".to_owned(),assets::get_asset_string("symbols.json").expect("failed to load symbol defintions")));
+
+    if let Some(filename) = filename {
+        let project_file = Path::new(filename).with_file_name("symbols.json");
+        if let Ok(text) = std::fs::read_to_string(&project_file) {
+            sources.push((project_file.display().to_string(),text));
+        }
+    }
+    if let Some(file_name) = &options.symbols {
+        sources.push((file_name.clone(),read_or_exit(std::fs::read_to_string(file_name),file_name)));
+    }
+
+    let mut error_count = 0;
+    for (name,text) in sources {
+        if let Err(errors) = table.add_json(&text) {
+            for err in &errors {
+                eprintln!("error: {}: {}",name,err);
+            }
+            error_count += errors.len();
+        }
+    }
+    if error_count > 0 {
+        eprintln!("error: aborting due to {} previous error(s)",error_count);
+        std::process::exit(1);
+    }
+
+    let categories: Vec<&str> = options.auto_signals.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    if let Err(err) = table.set_auto_categories(&categories) {
+        eprintln!("error: {}",err);
+        std::process::exit(1);
+    }
+    table
+}
+
+/// Runs the IR and the finished blueprint side by side, to check that the backend did not change what the module does.
+fn check_simulation(ir_mod: &ir::IRModule, blueprint: &blueprint::Blueprint, ports: &ir::BlueprintPorts, symbols: &symbols::SymbolTable,
+    ticks: u32, rng: &mut impl Rng) -> Result<(),String>
+{
+    let mut ir_sim = ir::Simulator::new(ir_mod);
+    let mut bp_sim = blueprint_sim::BlueprintSim::new(blueprint);
+    for _ in 0..ticks {
+        for (i,port) in ports.inputs.iter().enumerate() {
+            let value = rng.gen_range(-1000..=1000);
+            bp_sim.set_constant(port.entity_number,&port.signal,value);
+            // The port's constant combinator puts the value on both wires of a `@both` input, so it is read twice.
+            ir_sim.set_input(i,if port.color == ir::WireColor::Both { value * 2 } else { value });
+        }
+        for (i,port) in ports.outputs.iter().enumerate() {
+            let ir_value = ir_sim.get_output(i);
+            let bp_value = bp_sim.get_signal(port.entity_number,1,port.color,&port.signal);
+            if ir_value != bp_value {
+                return Err(format!("Simulation check failed at tick {}: output {} ({}) is {} in the IR, but {} in the blueprint.",
+                    ir_sim.tick(),i,symbols.signal_name(&port.signal),ir_value,bp_value));
+            }
+        }
+        ir_sim.step();
+        bp_sim.step();
+    }
+    Ok(())
+}
+
+/// Runs every test in the loaded files, and exits with an error if any of them failed.
+fn run_tests(modules: &ir::ModuleTable, files: &[loader::SourceFile], vcd_dir: Option<&str>) {
+    if let Some(dir) = vcd_dir {
+        if let Err(err) = std::fs::create_dir_all(dir) {
+            eprintln!("error: failed to create '{}': {}",dir,err);
+            std::process::exit(1);
+        }
+    }
+    let results = modules.run_tests(files,vcd_dir.map(Path::new));
+    println!("running {} test(s)",results.len());
+
+    let mut failures = Vec::new();
+    for result in &results {
+        let file = &files[result.file];
+        if let Some((msg,span)) = &result.failure {
+            println!("test {}: {} ... FAILED",file.name,result.name);
+            failures.push(diagnostic::Diagnostic::new(msg.clone(),*span).render(&file.name,file.text));
+        } else {
+            println!("test {}: {} ... ok",file.name,result.name);
+        }
+    }
+
+    println!();
+    for failure in &failures {
+        eprintln!("{}",failure);
+    }
+    let status = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!("test result: {}. {} passed; {} failed",status,results.len() - failures.len(),failures.len());
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
 fn main() {
 
     let options = CmdOptions::parse();
 
-    // TODO allow users to override or add additional symbols
-    let symbols_json = assets::get_asset_string("symbols.json").expect("failed to load symbol defintions");
-    symbols::load_symbols(&symbols_json);
+    let filename = match &options.command {
+        Some(Command::Test{filename,..}) => filename.clone(),
+        Some(Command::Repl{filename,..}) => Some(filename.clone()),
+        None => options.filename.clone()
+    };
+
+    let symbols = load_symbols(&options,filename.as_deref());
 
-    
-    let settings = Rc::new(CompileSettings{
+    let session = session::Session::new(CompileSettings{
         fold_constants: !(options.no_fold || options.no_opt),
         prune: !(options.no_prune || options.no_opt),
-        main_mod_name: options.mod_name
-    });
+        min_signals: options.min_signals || options.max_signals.is_some(),
+        max_signals: options.max_signals,
+        main_mod_name: options.mod_name.clone()
+    },symbols);
+    let settings = &session.settings;
 
-    let mut modules = HashMap::new();
-    let mut constants = HashMap::new();
+    let sources = loader::SourceArena::default();
+    let mut loader = loader::Loader::new(&session.symbols,&sources);
 
     // Load prelude
-    {
-        let prelude_source = assets::get_asset_string("std/prelude.cdl").expect("failed to load prelude");
-        let prelude_parsed = crate::parser::parse(&prelude_source);
-        ir::build_ir(prelude_parsed, settings.clone(), &mut modules, &mut constants);
+    let prelude = loader.load_embedded("std/prelude.cdl").expect("failed to load prelude");
+
+    // Load main source file, along with everything it imports
+    let root = filename.map(|filename| {
+        let source = if let Some(rom_offset) = options.rom_offset {
+            let bytes = read_or_exit(std::fs::read(&filename),&filename);
+            crate::rom_generator::make_rom(&bytes,rom_offset)
+        } else {
+            read_or_exit(std::fs::read_to_string(&filename),&filename)
+        };
+        loader.load_disk(Path::new(&filename), source)
+    });
+
+    if !loader.errors.is_empty() {
+        for err in &loader.errors {
+            eprintln!("{}",err);
+        }
+        eprintln!("error: aborting due to {} previous error(s)",loader.errors.len());
+        std::process::exit(1);
     }
 
-    let source = if let Some(rom_offset) = options.rom_offset {
-        let bytes = std::fs::read(options.filename).expect("failed to read file");
-        crate::rom_generator::make_rom(&bytes,rom_offset)
-    } else {
-        std::fs::read_to_string(options.filename).expect("failed to read file")
-    };
+    let mut modules = ir::ModuleTable::default();
 
-    // Load main source file
-    {
-        let parse_results = crate::parser::parse(&source);
-        ir::build_ir(parse_results, settings.clone(), &mut modules, &mut constants);
+    // Files are ordered so that imported modules are always built first.
+    for (index,file) in loader.files.iter_mut().enumerate() {
+        let namespace = ir::Namespace{
+            file: index,
+            imports: file.imports.clone(),
+            prelude: if index != prelude { Some(prelude) } else { None }
+        };
+        let items = std::mem::take(&mut file.items);
+        if let Err(err) = ir::build_ir(items, settings.clone(), &namespace, &mut modules) {
+            eprintln!("{}",err.render(&file.name,file.text));
+            eprintln!("error: aborting due to previous error");
+            std::process::exit(1);
+        }
     }
 
-    if let Some(ir_mod) = modules.get_mut(&settings.main_mod_name) {
-        ir_mod.select_colors();
-        ir_mod.select_symbols();
+    if let Some(Command::Test{vcd,..}) = &options.command {
+        run_tests(&modules,&loader.files,vcd.as_deref());
+        return;
+    }
+    let root = root.expect("no source file to compile");
+
+    if let Some(Command::Repl{mod_name,..}) = &options.command {
+        if let Some(ir_mod) = modules.get(root, mod_name) {
+            ir::Repl::new(ir_mod).run();
+        } else if modules.is_template(root, mod_name) {
+            eprintln!("error: Module '{}' has compile-time parameters, it can only be used as a submodule.",mod_name);
+            std::process::exit(1);
+        } else {
+            eprintln!("error: Module '{}' not found.",mod_name);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(ir_mod) = modules.get_mut(root, &settings.main_mod_name) {
+        let res = ir_mod.select_colors(&loader.files)
+            .and_then(|_| ir_mod.select_symbols(&session.symbols,&loader.files));
+        if let Err(err) = res {
+            println!();
+            eprintln!("error: {}",err);
+            std::process::exit(1);
+        }
         ir_mod.layout_nodes();
 
-        let bp_obj = ir_mod.to_blueprint();
+        let (bp_obj,ports) = ir_mod.to_blueprint_with_ports(&session.symbols);
+        if let Some(ticks) = options.check_sim {
+            print!("Simulation check... ");
+            if let Err(err) = check_simulation(ir_mod,&bp_obj,&ports,&session.symbols,ticks,&mut rand::thread_rng()) {
+                println!();
+                eprintln!("error: {}",err);
+                std::process::exit(1);
+            }
+            println!("Done, outputs matched for {} ticks.",ticks);
+        }
 
         let bp_string = blueprint::write_blueprint(bp_obj);
         println!();
         println!("{}",bp_string);
+    } else if modules.is_template(root, &settings.main_mod_name) {
+        eprintln!("error: Main module '{}' has compile-time parameters, it can only be used as a submodule.",settings.main_mod_name);
+        std::process::exit(1);
     } else {
-        panic!("Main module '{}' not found.",settings.main_mod_name);
+        eprintln!("error: Main module '{}' not found.",settings.main_mod_name);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::ir::test_util;
+
+    fn check_module(symbols: &symbols::SymbolTable, source: &str, name: &str) {
+        let module = test_util::compile(symbols,source,name).unwrap();
+        let (blueprint,ports) = module.to_blueprint_with_ports(symbols);
+        let mut rng = StdRng::seed_from_u64(0);
+        if let Err(err) = check_simulation(&module,&blueprint,&ports,symbols,200,&mut rng) {
+            panic!("Module '{}': {}",name,err);
+        }
+    }
+
+    #[test]
+    fn prelude_blueprints_match_ir() {
+        let symbols = test_util::symbols();
+        let names = test_util::prelude_modules(&symbols);
+        assert!(names.iter().any(|name| name == "cell"));
+        for name in &names {
+            check_module(&symbols,"",name);
+        }
+    }
+
+    #[test]
+    fn untyped_output_from_submodule() {
+        let symbols = test_util::symbols();
+        check_module(&symbols,"mod main(a) {\n    let clk = clock(4);\n    let v = cell(clk,1,a);\n    output(v);\n}","main");
     }
 }
@@ -1,29 +1,137 @@
-use crate::lexer::{Lexer, LexToken};
+use crate::lexer::{Lexer, LexToken, Token};
 use crate::common::{BinOp,UnaryOp};
-use crate::symbols;
-
-use std::iter::Peekable;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::symbols::SymbolTable;
 
 #[derive(Debug)]
 pub struct Module<'a> {
     pub name: &'a str,
+    pub name_span: Span,
+    /// Whether the module is visible to files that import this one.
+    pub public: bool,
+    /// Names of compile-time parameters, like `WIDTH` in `mod shifter<WIDTH>(x)`.
+    pub params: Vec<&'a str>,
     pub arg_names: Vec<&'a str>,
+    /// Array arguments, like `rows[8]`, have a length.
+    pub arg_sizes: Vec<Option<Expr<'a>>>,
+    pub arg_spans: Vec<Span>,
     pub stmts: Vec<Statement<'a>>,
     pub arg_types: Vec<Option<u32>>,
-    pub ret_types: Option<Vec<Option<u32>>>
+    /// One type for each declared output. Array outputs are expanded when the module is built.
+    pub ret_types: Option<Vec<Option<u32>>>,
+    /// Array outputs, like `$X[8]`, have a length.
+    pub ret_sizes: Vec<Option<Expr<'a>>>,
+    /// Wire colors the ports are connected with, like `x: $A @green`. Only used by the top-level module.
+    pub arg_colors: Vec<Option<PortColor>>,
+    pub ret_colors: Vec<Option<PortColor>>
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PortColor {
+    Red,
+    Green,
+    /// An input that reads the sum of both wires.
+    Both
 }
 
 pub enum ParseItem<'a> {
     Module(Module<'a>),
-    Constant(&'a str,i64)
+    /// A named constant. The span covers the expression, for reporting evaluation errors.
+    Constant(&'a str,Expr<'a>,Span),
+    Enum(Enum<'a>),
+    /// An import of another source file, with an optional alias. The span covers the path string.
+    Use(&'a str,Option<&'a str>,Span),
+    Test(Test<'a>)
+}
+
+/// A test bench, like `test counts { drive x = 1; run 4; expect out == 4; }`.
+pub struct Test<'a> {
+    pub name: &'a str,
+    /// The module under test and its compile-time parameters, from `test name for module { ... }`.
+    /// If this is missing, the test is for the module declared just before it.
+    pub module: Option<(ItemPath<'a>,Vec<Expr<'a>>)>,
+    /// Statements, with spans to point at when they fail.
+    pub stmts: Vec<(TestStatement<'a>,Span)>,
+    /// The span of the test's name.
+    pub span: Span
+}
+
+pub enum TestStatement<'a> {
+    /// Sets an input to a value, which is held until it is driven again.
+    Drive(Binding<'a>,Expr<'a>),
+    /// Simulates a number of ticks.
+    Run(Expr<'a>),
+    /// Fails the test if the expression is zero.
+    Expect(Expr<'a>)
+}
+
+/// A group of related constants, like `enum Alu { Or = 1, And, Xor }`.
+/// Members without a value are numbered from the previous member, starting at zero.
+pub struct Enum<'a> {
+    pub name: &'a str,
+    /// Member names, explicit values, and the span of each name.
+    pub members: Vec<(&'a str,Option<Expr<'a>>,Span)>
+}
+
+/// A possibly-qualified name, like `min` or `std::min`.
+#[derive(Debug,Clone,Copy)]
+pub struct ItemPath<'a> {
+    pub namespace: Option<&'a str>,
+    pub name: &'a str
+}
+
+impl<'a> std::fmt::Display for ItemPath<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(namespace) = self.namespace {
+            write!(f,"{}::",namespace)?;
+        }
+        write!(f,"{}",self.name)
+    }
+}
+
+/// The target of a `let` or an assignment, either a plain name or an indexed name like `V[i]`.
+/// In a `let` the index is the length of a new array, and in an assignment it picks an element.
+#[derive(Debug)]
+pub struct Binding<'a> {
+    pub name: &'a str,
+    pub index: Option<Expr<'a>>,
+    pub span: Span
 }
 
 #[derive(Debug)]
 pub enum Statement<'a> {
     Terminator,
     Empty,
-    VarBinding(Vec<&'a str>,Expr<'a>),
-    Output(Vec<Expr<'a>>)
+    VarBinding(Vec<Binding<'a>>,Expr<'a>,Span),
+    /// An array whose elements are assigned one at a time, like `let V[16];`.
+    ArrayDecl(&'a str,Expr<'a>,Span),
+    /// Assigns elements of an array declared with `let V[16];`, like `V[i] = ...` or `(r[i],c[i]) = ...`.
+    Assign(Vec<Binding<'a>>,Expr<'a>,Span),
+    Output(Vec<Expr<'a>>,Span),
+    /// A constant that is only visible inside the module.
+    Const(&'a str,Expr<'a>,Span),
+    /// A loop over a constant range, unrolled at compile time: variable, start, end (exclusive), body.
+    For(&'a str,Expr<'a>,Expr<'a>,Vec<Statement<'a>>)
+}
+
+impl<'a> Statement<'a> {
+    /// The source of the statement. Blocks and constants are not tracked.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Statement::VarBinding(_,_,span) |
+            Statement::ArrayDecl(_,_,span) |
+            Statement::Assign(_,_,span) |
+            Statement::Output(_,span) => Some(*span),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MatchArm<'a> {
+    Case(BinOp,Expr<'a>,Expr<'a>),
+    /// Arms generated by a compile-time loop: variable, start, end (exclusive), arms.
+    For(&'a str,Expr<'a>,Expr<'a>,Vec<MatchArm<'a>>)
 }
 
 #[derive(Debug)]
@@ -34,216 +142,609 @@ pub enum Expr<'a> {
     UnOp(UnaryOp,Box<Expr<'a>>),
     // Note: Optional false-exprs are deprecated, user must specify 0 instead.
     If(Box<Expr<'a>>,Box<Expr<'a>>,Option<Box<Expr<'a>>>),
-    Match(Box<Expr<'a>>,Vec<(BinOp,Expr<'a>,Expr<'a>)>),
-    SubModule(String,Vec<Expr<'a>>)
+    Match(Box<Expr<'a>>,Vec<MatchArm<'a>>),
+    /// A qualified constant, like the enum member `Alu::Or`.
+    Path(ItemPath<'a>),
+    /// An indexed binding, like `V[i]`. Indexing an array with a non-constant index selects an element at runtime.
+    Index(&'a str,Box<Expr<'a>>),
+    /// Module name, compile-time parameters, and arguments.
+    SubModule(ItemPath<'a>,Vec<Expr<'a>>,Vec<Expr<'a>>)
 }
 
-struct Parser<'a> {
-    lexer: Peekable<Lexer<'a>>
+type ParseResult<T> = Result<T,Diagnostic>;
+
+struct Parser<'a,'s> {
+    lexer: Lexer<'a>,
+    symbols: &'s SymbolTable,
+    peeked: Option<Token<'a>>,
+    /// Span of the most recently consumed token.
+    last_span: Span,
+    last_kind: Option<LexToken<'a>>,
+    /// Number of unclosed parens and braces among consumed tokens, used for error recovery.
+    depth: u32,
+    errors: Vec<Diagnostic>
 }
 
-impl<'a> Parser<'a> {
-    fn new(lexer: Lexer<'a>) -> Self {
-        Self{lexer: lexer.peekable()}
+impl<'a,'s> Parser<'a,'s> {
+    fn new(lexer: Lexer<'a>, symbols: &'s SymbolTable) -> Self {
+        Self{lexer, symbols, peeked: None, last_span: Span::default(), last_kind: None, depth: 0, errors: Vec::new()}
     }
 
-    fn take(&mut self, tok: LexToken) {
-        let present = self.next();
-        if present != tok {
-            panic!("Expected {:?}, found {:?}.",tok,present);
+    fn unexpected(&self, expected: &str, found: Token) -> Diagnostic {
+        Diagnostic::new(format!("Expected {}, found {}.",expected,found.kind),found.span)
+            .with_label(format!("expected {}",expected))
+    }
+
+    fn take(&mut self, tok: LexToken) -> ParseResult<()> {
+        let present = self.next_token()?;
+        if present.kind != tok {
+            return Err(self.unexpected(&tok.to_string(),present));
         }
+        Ok(())
     }
 
-    fn take_ident(&mut self) -> &'a str {
-        let present = self.next();
-        if let LexToken::Ident(ident_str) = present {
-            ident_str
+    fn take_ident(&mut self) -> ParseResult<&'a str> {
+        let present = self.next_token()?;
+        if let LexToken::Ident(ident_str) = present.kind {
+            Ok(ident_str)
         } else {
-            panic!("Expected ident, found {:?}.",present);
+            Err(self.unexpected("identifier",present))
         }
     }
 
-    fn take_symbol(&mut self) -> Option<u32> {
-        let present = self.next();
-        if let LexToken::Symbol(symbol_str) = present {
-            Some(symbols::symbol_index_from_identifier(symbol_str))
+    fn take_symbol(&mut self) -> ParseResult<Option<u32>> {
+        let present = self.next_token()?;
+        if let LexToken::Symbol(symbol_str) = present.kind {
+            if let Some(index) = self.symbols.symbol_index_from_identifier(symbol_str) {
+                Ok(Some(index))
+            } else {
+                Err(Diagnostic::new(format!("Signal name `${}` does not exist.",symbol_str),present.span)
+                    .with_label("unknown signal".to_owned()))
+            }
         } else {
-            panic!("Expected symbol, found {:?}.",present);
+            Err(self.unexpected("symbol",present))
         }
     }
 
-    fn take_comma_or_close_paren(&mut self) -> bool {
-        let present = self.next();
-        match present {
-            LexToken::OpComma => false,
-            LexToken::OpParenClose => true,
-            _ => panic!("Expected comma or close paren, found {:?}.",present)
+    fn take_comma_or_close_paren(&mut self) -> ParseResult<bool> {
+        let present = self.next_token()?;
+        match present.kind {
+            LexToken::OpComma => Ok(false),
+            LexToken::OpParenClose => Ok(true),
+            _ => Err(self.unexpected("`,` or `)`",present))
         }
     }
 
-    fn next(&mut self) -> LexToken<'a> {
-        self.lexer.next().expect("Expected token, found EOF.")
+    fn take_comma_or_close_angle(&mut self) -> ParseResult<bool> {
+        let present = self.next_token()?;
+        match present.kind {
+            LexToken::OpComma => Ok(false),
+            LexToken::OpCmpGt => Ok(true),
+            _ => Err(self.unexpected("`,` or `>`",present))
+        }
+    }
+
+    fn fill_peek(&mut self) -> ParseResult<()> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next().transpose()?;
+        }
+        Ok(())
+    }
+
+    fn eof_error(&self) -> Diagnostic {
+        let offset = self.lexer.offset();
+        Diagnostic::new("Expected token, found end of file.".to_owned(),Span::new(offset,offset))
+    }
+
+    fn next_token(&mut self) -> ParseResult<Token<'a>> {
+        self.fill_peek()?;
+        if let Some(tok) = self.peeked.take() {
+            self.last_span = tok.span;
+            self.last_kind = Some(tok.kind);
+            match tok.kind {
+                LexToken::OpParenOpen | LexToken::OpBraceOpen | LexToken::OpBracketOpen => self.depth += 1,
+                LexToken::OpParenClose | LexToken::OpBraceClose | LexToken::OpBracketClose => self.depth = self.depth.saturating_sub(1),
+                _ => ()
+            }
+            Ok(tok)
+        } else {
+            Err(self.eof_error())
+        }
     }
 
-    fn peek(&mut self) -> LexToken<'a> {
-        *self.lexer.peek().expect("Expected token, found EOF.")
+    fn next(&mut self) -> ParseResult<LexToken<'a>> {
+        self.next_token().map(|tok| tok.kind)
     }
 
-    fn is_eof(&mut self) -> bool {
-        self.lexer.peek().is_none()   
+    fn peek_token(&mut self) -> ParseResult<Token<'a>> {
+        self.fill_peek()?;
+        self.peeked.ok_or_else(|| self.eof_error())
+    }
+
+    fn peek(&mut self) -> ParseResult<LexToken<'a>> {
+        self.peek_token().map(|tok| tok.kind)
+    }
+
+    /// Peeks without failing. Lexer errors are recorded and skipped.
+    fn peek_recovering(&mut self) -> Option<LexToken<'a>> {
+        loop {
+            match self.fill_peek() {
+                Ok(()) => return self.peeked.map(|tok| tok.kind),
+                Err(err) => self.errors.push(err)
+            }
+        }
+    }
+
+    /// Whether a token is the first one on its line.
+    fn starts_line(&self, span: Span) -> bool {
+        let before = &self.lexer.source()[..span.start];
+        before.rsplit('\n').next().unwrap_or("").trim().is_empty()
+    }
+
+    /// Skips to the end of a broken statement. Stops after a `;`, before a `}` at the statement's
+    /// own nesting depth, or before a line starting with `let` or `const`. Brackets left unclosed by
+    /// the broken statement are forgotten. Returns false if the block was closed, or a new item or
+    /// EOF was reached instead.
+    fn recover_stmt(&mut self, base_depth: u32) -> bool {
+        // The error may have been raised on the terminator itself.
+        match self.last_kind {
+            Some(LexToken::OpSemicolon) => {
+                self.depth = base_depth;
+                return true;
+            },
+            Some(LexToken::OpBraceClose) if self.depth < base_depth => return false,
+            // A `}` on its own line most likely closes the block, even if a bracket was left open.
+            Some(LexToken::OpBraceClose) if self.starts_line(self.last_span) => {
+                self.depth = base_depth.saturating_sub(1);
+                return false;
+            },
+            _ => ()
+        }
+        while let Some(tok) = self.peek_recovering() {
+            match tok {
+                LexToken::KeyMod | LexToken::KeyPub => return false,
+                LexToken::KeyConst | LexToken::KeyUse | LexToken::KeyEnum | LexToken::Ident("test") if self.depth == 0 => return false,
+                LexToken::KeyLet | LexToken::KeyConst if self.peeked.is_some_and(|tok| self.starts_line(tok.span)) => {
+                    self.depth = base_depth;
+                    return true;
+                },
+                LexToken::OpBraceClose if self.depth <= base_depth => return true,
+                LexToken::OpSemicolon => {
+                    self.next().ok();
+                    self.depth = base_depth;
+                    return true;
+                },
+                _ => {
+                    self.next().ok();
+                }
+            }
+        }
+        false
+    }
+
+    /// Skips to the start of the next top-level item.
+    fn recover_item(&mut self) {
+        while let Some(tok) = self.peek_recovering() {
+            match tok {
+                LexToken::KeyMod | LexToken::KeyPub => break,
+                LexToken::KeyConst | LexToken::KeyUse | LexToken::KeyEnum | LexToken::Ident("test") if self.depth == 0 => break,
+                _ => {
+                    self.next().ok();
+                }
+            }
+        }
+        self.depth = 0;
     }
 }
 
-pub fn parse<'a>(source: &'a str) -> Vec<ParseItem<'a>> {
+/// Parses a source file. On failure, returns every syntax error that was found.
+/// Signal names are resolved using the given symbol table.
+pub fn parse<'a>(source: &'a str, symbols: &SymbolTable) -> Result<Vec<ParseItem<'a>>,Vec<Diagnostic>> {
 
     let lexer = Lexer::new(source);
-    let mut parser = Parser::new(lexer);
+    let mut parser = Parser::new(lexer,symbols);
 
-    // Module declaration
     let mut results = Vec::new();
-    while !parser.is_eof() {
-        if parser.peek() == LexToken::KeyConst {
-            parser.take(LexToken::KeyConst);
+    while parser.peek_recovering().is_some() {
+        match parse_item(&mut parser) {
+            Ok(item) => results.push(item),
+            Err(err) => {
+                parser.errors.push(err);
+                parser.recover_item();
+            }
+        }
+    }
 
-            let name = parser.take_ident();
-            parser.take(LexToken::OpAssign);
-            
-            let expr = parse_expr(&mut parser);
+    if parser.errors.is_empty() {
+        Ok(results)
+    } else {
+        Err(parser.errors)
+    }
+}
+
+fn parse_item<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<ParseItem<'a>> {
+    if parser.peek()? == LexToken::KeyUse {
+        parser.take(LexToken::KeyUse)?;
+
+        let path_tok = parser.next_token()?;
+        let path = if let LexToken::Str(path) = path_tok.kind {
+            path
+        } else {
+            return Err(parser.unexpected("file path",path_tok));
+        };
+
+        let alias = if parser.peek()? == LexToken::KeyAs {
+            parser.take(LexToken::KeyAs)?;
+            Some(parser.take_ident()?)
+        } else {
+            None
+        };
+
+        parser.take(LexToken::OpSemicolon)?;
+        return Ok(ParseItem::Use(path,alias,path_tok.span));
+    }
+
+    if parser.peek()? == LexToken::KeyConst {
+        parser.take(LexToken::KeyConst)?;
+
+        let name = parser.take_ident()?;
+        parser.take(LexToken::OpAssign)?;
+        
+        let expr_start = parser.peek_token()?.span;
+        let expr = parse_expr(parser)?;
+        let expr_span = expr_start.to(parser.last_span);
+
+        parser.take(LexToken::OpSemicolon)?;
+        return Ok(ParseItem::Constant(name,expr,expr_span));
+    }
 
-            parser.take(LexToken::OpSemicolon);
+    if parser.peek()? == LexToken::KeyEnum {
+        parser.take(LexToken::KeyEnum)?;
 
-            if let Expr::Constant(num) = expr {
-                results.push(ParseItem::Constant(name,num));
+        let name = parser.take_ident()?;
+        parser.take(LexToken::OpBraceOpen)?;
+
+        let mut members = Vec::new();
+        while parser.peek()? != LexToken::OpBraceClose {
+            let member = parser.take_ident()?;
+            let span = parser.last_span;
+            let value = if parser.peek()? == LexToken::OpAssign {
+                parser.take(LexToken::OpAssign)?;
+                Some(parse_expr(parser)?)
             } else {
-                panic!("constants can currently only be literal values, sorry")
+                None
+            };
+            members.push((member,value,span));
+
+            // The comma after the last member is optional.
+            if parser.peek()? != LexToken::OpBraceClose {
+                parser.take(LexToken::OpComma)?;
             }
-            continue;
         }
+        parser.take(LexToken::OpBraceClose)?;
+        return Ok(ParseItem::Enum(Enum{name,members}));
+    }
 
-        parser.take(LexToken::KeyMod);
-        let mod_name = parser.take_ident();
-        let mut mod_args = Vec::new();
-        let mut mod_stmts = Vec::new();
-        
-        // Arguments
-        let mut arg_types = Vec::new();
-        parser.take(LexToken::OpParenOpen);
-        if parser.peek() != LexToken::OpParenClose {
-            loop {
-                mod_args.push(parser.take_ident());
-                let ty = if parser.peek() == LexToken::OpColon {
-                    parser.take(LexToken::OpColon);
-                    parser.take_symbol()
-                } else {
-                    None
-                };
-                arg_types.push(ty);
+    // `test` is only a keyword at the start of an item, so it can still be used as a name elsewhere.
+    if parser.peek()? == LexToken::Ident("test") {
+        return parse_test(parser).map(ParseItem::Test);
+    }
 
-                if parser.take_comma_or_close_paren() {
-                    break;
-                }
+    let public = if parser.peek()? == LexToken::KeyPub {
+        parser.take(LexToken::KeyPub)?;
+        true
+    } else {
+        false
+    };
+
+    parser.take(LexToken::KeyMod)?;
+    let mod_name = parser.take_ident()?;
+    let name_span = parser.last_span;
+    let mut mod_params = Vec::new();
+    let mut mod_args = Vec::new();
+    let mut arg_sizes = Vec::new();
+    let mut arg_spans = Vec::new();
+    
+    // Compile-time parameters
+    if parser.peek()? == LexToken::OpCmpLt {
+        parser.take(LexToken::OpCmpLt)?;
+        loop {
+            mod_params.push(parser.take_ident()?);
+            if parser.take_comma_or_close_angle()? {
+                break;
             }
-        } else {
-            parser.take(LexToken::OpParenClose);
         }
-        
-        let ret_types = if parser.peek() == LexToken::OpThinArrow {
-            parser.take(LexToken::OpThinArrow);
+    }
+
+    // Arguments
+    let mut arg_types = Vec::new();
+    let mut arg_colors = Vec::new();
+    parser.take(LexToken::OpParenOpen)?;
+    if parser.peek()? != LexToken::OpParenClose {
+        loop {
+            mod_args.push(parser.take_ident()?);
+            arg_spans.push(parser.last_span);
+            let size = if parser.peek()? == LexToken::OpBracketOpen {
+                parser.take(LexToken::OpBracketOpen)?;
+                let size = parse_expr(parser)?;
+                parser.take(LexToken::OpBracketClose)?;
+                Some(size)
+            } else {
+                None
+            };
+            arg_sizes.push(size);
 
-            if parser.peek() == LexToken::OpParenOpen {
-                parser.take(LexToken::OpParenOpen);
+            let ty = if parser.peek()? == LexToken::OpColon {
+                parser.take(LexToken::OpColon)?;
+                parser.take_symbol()?
+            } else {
+                None
+            };
+            arg_types.push(ty);
+            arg_colors.push(parse_port_color(parser)?);
 
-                if parser.peek() == LexToken::OpParenClose {
-                    parser.take(LexToken::OpParenClose);
-                    Some(vec!())
-                } else {
-                    let mut result = vec!(parser.take_symbol());
-                    while !parser.take_comma_or_close_paren() {
-                        result.push(parser.take_symbol());
+            if parser.take_comma_or_close_paren()? {
+                break;
+            }
+        }
+    } else {
+        parser.take(LexToken::OpParenClose)?;
+    }
+    
+    let mut ret_sizes = Vec::new();
+    let mut ret_colors = Vec::new();
+    let ret_types = if parser.peek()? == LexToken::OpThinArrow {
+        parser.take(LexToken::OpThinArrow)?;
+
+        if parser.peek()? == LexToken::OpParenOpen {
+            parser.take(LexToken::OpParenOpen)?;
+
+            if parser.peek()? == LexToken::OpParenClose {
+                parser.take(LexToken::OpParenClose)?;
+                Some(vec!())
+            } else {
+                let mut result = Vec::new();
+                loop {
+                    parse_ret_type(parser,&mut result,&mut ret_sizes,&mut ret_colors)?;
+                    if parser.take_comma_or_close_paren()? {
+                        break;
                     }
-                    Some(result)
                 }
-            } else {
-                Some(vec!(parser.take_symbol()))
+                Some(result)
             }
         } else {
-            None
+            let mut result = Vec::new();
+            parse_ret_type(parser,&mut result,&mut ret_sizes,&mut ret_colors)?;
+            Some(result)
+        }
+    } else {
+        None
+    };
+
+    parser.take(LexToken::OpBraceOpen)?;
+    let mod_stmts = parse_block(parser);
+    Ok(ParseItem::Module(Module{
+        name: mod_name,
+        name_span,
+        public,
+        params: mod_params,
+        arg_names: mod_args,
+        arg_sizes,
+        arg_spans,
+        stmts: mod_stmts,
+        arg_types,
+        ret_types,
+        ret_sizes,
+        arg_colors,
+        ret_colors
+    }))
+}
+
+fn parse_test<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Test<'a>> {
+    parser.next()?;
+    let name = parser.take_ident()?;
+    let span = parser.last_span;
+
+    let module = if parser.peek()? == LexToken::KeyFor {
+        parser.take(LexToken::KeyFor)?;
+        let first = parser.take_ident()?;
+        let (path,params,_) = parse_item_path(parser,first)?;
+        Some((path,params))
+    } else {
+        None
+    };
+
+    parser.take(LexToken::OpBraceOpen)?;
+    let mut stmts = Vec::new();
+    loop {
+        let tok = parser.next_token()?;
+        let stmt = match tok.kind {
+            LexToken::Ident("drive") => {
+                let input = parse_binding(parser)?;
+                parser.take(LexToken::OpAssign)?;
+                TestStatement::Drive(input,parse_expr(parser)?)
+            },
+            LexToken::Ident("run") => TestStatement::Run(parse_expr(parser)?),
+            LexToken::Ident("expect") => TestStatement::Expect(parse_expr(parser)?),
+            LexToken::OpSemicolon => continue,
+            LexToken::OpBraceClose => break,
+            _ => return Err(parser.unexpected("`drive`, `run` or `expect`",tok))
         };
+        stmts.push((stmt,tok.span.to(parser.last_span)));
+        parser.take(LexToken::OpSemicolon)?;
+    }
+    Ok(Test{name,module,stmts,span})
+}
 
-        parser.take(LexToken::OpBraceOpen);
-        loop {
-            let stmt = parse_stmt(&mut parser);
-            match stmt {
-                Statement::Empty => (),
-                Statement::Terminator => break,
-                _ => mod_stmts.push(stmt)
+/// Parses an optional port color, like `@green`.
+fn parse_port_color(parser: &mut Parser) -> ParseResult<Option<PortColor>> {
+    if parser.peek()? != LexToken::OpAt {
+        return Ok(None);
+    }
+    parser.take(LexToken::OpAt)?;
+    let tok = parser.next_token()?;
+    match tok.kind {
+        LexToken::Ident("red") => Ok(Some(PortColor::Red)),
+        LexToken::Ident("green") => Ok(Some(PortColor::Green)),
+        LexToken::Ident("both") => Ok(Some(PortColor::Both)),
+        _ => Err(parser.unexpected("`red`, `green` or `both`",tok))
+    }
+}
+
+/// Parses a single return type. Array outputs are written as `$X[8]`, which repeats the type and color.
+fn parse_ret_type<'a>(parser: &mut Parser<'a,'_>, types: &mut Vec<Option<u32>>, sizes: &mut Vec<Option<Expr<'a>>>,
+    colors: &mut Vec<Option<PortColor>>
+) -> ParseResult<()> {
+    types.push(parser.take_symbol()?);
+    let size = if parser.peek()? == LexToken::OpBracketOpen {
+        parser.take(LexToken::OpBracketOpen)?;
+        let size = parse_expr(parser)?;
+        parser.take(LexToken::OpBracketClose)?;
+        Some(size)
+    } else {
+        None
+    };
+    sizes.push(size);
+    let color_start = parser.peek_token()?.span;
+    let color = parse_port_color(parser)?;
+    if color == Some(PortColor::Both) {
+        return Err(Diagnostic::new("Outputs can only be connected with one wire color, `@both` is only allowed on inputs.".to_owned(),
+            color_start.to(parser.last_span)));
+    }
+    colors.push(color);
+    Ok(())
+}
+
+/// Parses statements up to and including the closing `}` of a block.
+/// Broken statements are recorded as errors and skipped, so every one of them gets reported.
+fn parse_block<'a>(parser: &mut Parser<'a,'_>) -> Vec<Statement<'a>> {
+    let mut stmts = Vec::new();
+    let body_depth = parser.depth;
+    loop {
+        match parse_stmt(parser) {
+            Ok(Statement::Empty) => (),
+            Ok(Statement::Terminator) => break,
+            Ok(stmt) => stmts.push(stmt),
+            Err(err) => {
+                parser.errors.push(err);
+                if !parser.recover_stmt(body_depth) {
+                    break;
+                }
             }
         }
-        results.push(ParseItem::Module(Module{
-            name: mod_name,
-            arg_names: mod_args,
-            stmts: mod_stmts,
-            arg_types,
-            ret_types
-        }));
     }
+    stmts
+}
+
+fn parse_binding<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Binding<'a>> {
+    let name = parser.take_ident()?;
+    let start = parser.last_span;
+    let index = if parser.peek()? == LexToken::OpBracketOpen {
+        parser.take(LexToken::OpBracketOpen)?;
+        let index = parse_expr(parser)?;
+        parser.take(LexToken::OpBracketClose)?;
+        Some(index)
+    } else {
+        None
+    };
+    Ok(Binding{name,index,span: start.to(parser.last_span)})
+}
+
+/// Parses a single binding, or a list of them in parentheses.
+fn parse_bindings<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Vec<Binding<'a>>> {
+    if parser.peek()? != LexToken::OpParenOpen {
+        return Ok(vec!(parse_binding(parser)?));
+    }
+    let mut bindings = Vec::new();
+    parser.take(LexToken::OpParenOpen)?;
+    // Don't worry about the empty case, why have zero vars?
+    loop {
+        bindings.push(parse_binding(parser)?);
+        if parser.take_comma_or_close_paren()? {
+            break;
+        }
+    }
+    Ok(bindings)
+}
 
-    results
+/// Parses the `i in 0..16` part of a for loop.
+fn parse_for_range<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<(&'a str,Expr<'a>,Expr<'a>)> {
+    let var = parser.take_ident()?;
+    parser.take(LexToken::KeyIn)?;
+    let start = parse_expr(parser)?;
+    parser.take(LexToken::OpRange)?;
+    let end = parse_expr(parser)?;
+    Ok((var,start,end))
 }
 
-fn parse_stmt<'a>(parser: &mut Parser<'a>) -> Statement<'a> {
-    let tok = parser.next();
-    match tok {
+fn parse_stmt<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Statement<'a>> {
+    // Assignments start with their targets, like `V[i] = ...`.
+    if matches!(parser.peek()?, LexToken::Ident(_) | LexToken::OpParenOpen) {
+        let start = parser.peek_token()?.span;
+        let targets = parse_bindings(parser)?;
+        if let Some(target) = targets.iter().find(|target| target.index.is_none()) {
+            return Err(Diagnostic::new(format!("Only array elements can be assigned, use `let {} = ...` to bind a new name.",target.name),
+                target.span));
+        }
+        parser.take(LexToken::OpAssign)?;
+        let expr = parse_expr(parser)?;
+        return Ok(Statement::Assign(targets,expr,start.to(parser.last_span)));
+    }
+
+    let tok = parser.next_token()?;
+    Ok(match tok.kind {
         LexToken::KeyOutput => {
             let mut out_args = Vec::new();
-            parser.take(LexToken::OpParenOpen);
+            parser.take(LexToken::OpParenOpen)?;
             // Don't worry about the empty case, why output nothing?
             loop {
-                out_args.push(parse_expr(parser));
-                if parser.take_comma_or_close_paren() {
+                out_args.push(parse_expr(parser)?);
+                if parser.take_comma_or_close_paren()? {
                     break;
                 }
             }
-            Statement::Output(out_args)
+            Statement::Output(out_args,tok.span.to(parser.last_span))
         },
         LexToken::KeyLet => {
-            if parser.peek() == LexToken::OpParenOpen {
-                let mut idents = Vec::new();
-                parser.take(LexToken::OpParenOpen);
-                // Don't worry about the empty case, why have zero vars?
-                loop {
-                    idents.push(parser.take_ident());
-                    if parser.take_comma_or_close_paren() {
-                        break;
-                    }
-                }
-                parser.take(LexToken::OpAssign);
-                Statement::VarBinding(idents,parse_expr(parser))
-            } else {
-                let ident = parser.take_ident();
-                parser.take(LexToken::OpAssign);
-                Statement::VarBinding(vec!(ident),parse_expr(parser))
+            let mut bindings = parse_bindings(parser)?;
+            if bindings.len() == 1 && bindings[0].index.is_some() && parser.peek()? == LexToken::OpSemicolon {
+                let binding = bindings.pop().unwrap();
+                return Ok(Statement::ArrayDecl(binding.name,binding.index.unwrap(),tok.span.to(parser.last_span)));
             }
+            parser.take(LexToken::OpAssign)?;
+            Statement::VarBinding(bindings,parse_expr(parser)?,tok.span.to(parser.last_span))
+        },
+        LexToken::KeyConst => {
+            let name = parser.take_ident()?;
+            parser.take(LexToken::OpAssign)?;
+            let expr_start = parser.peek_token()?.span;
+            let expr = parse_expr(parser)?;
+            Statement::Const(name,expr,expr_start.to(parser.last_span))
+        },
+        LexToken::KeyFor => {
+            let (var,start,end) = parse_for_range(parser)?;
+            parser.take(LexToken::OpBraceOpen)?;
+            Statement::For(var,start,end,parse_block(parser))
         },
         LexToken::OpSemicolon => Statement::Empty,
         LexToken::OpBraceClose => Statement::Terminator,
-        _ => panic!("Expected statment, found {:?}.",tok)
-    }
+        _ => return Err(parser.unexpected("statement",tok))
+    })
 }
 
-fn parse_expr<'a>(parser: &mut Parser<'a>) -> Expr<'a> {
+fn parse_expr<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Expr<'a>> {
 
     let mut expr_stack: Vec<Expr> = Vec::new();
     let mut op_stack: Vec<BinOp> = Vec::new();
     let mut ternary = false;
 
-    expr_stack.push(parse_leaf(parser));
+    expr_stack.push(parse_leaf(parser)?);
 
     loop {
         // try parsing an operator, or end the expression
-        let next_tok = parser.peek();
+        let next_tok = parser.peek_token()?;
 
-        let new_op = match next_tok {
+        let new_op = match next_tok.kind {
             LexToken::OpAdd => BinOp::Add,
             LexToken::OpSub => BinOp::Sub,
             LexToken::OpMul => BinOp::Mul,
@@ -265,19 +766,22 @@ fn parse_expr<'a>(parser: &mut Parser<'a>) -> Expr<'a> {
             LexToken::OpCmpGeq => BinOp::CmpGeq,
 
             LexToken::OpQuestion => {
-                parser.next();
+                parser.next()?;
                 ternary = true;
                 break;
             },
 
             // sane expression terminators
             LexToken::OpParenClose |
+            LexToken::OpBraceOpen |
             LexToken::OpBraceClose |
+            LexToken::OpBracketClose |
+            LexToken::OpRange |
             LexToken::OpSemicolon |
             LexToken::OpColon |
             LexToken::OpComma |
             LexToken::OpMatchArrow => break,
-            _ => panic!("Expected operator, found {:?}",next_tok)
+            _ => return Err(parser.unexpected("operator",next_tok))
         };
 
         while let Some(top_op) = op_stack.last() {
@@ -295,10 +799,10 @@ fn parse_expr<'a>(parser: &mut Parser<'a>) -> Expr<'a> {
         op_stack.push(new_op);
 
         // advance
-        parser.next();
+        parser.next()?;
 
         // rhs of the parsed operator
-        expr_stack.push(parse_leaf(parser));
+        expr_stack.push(parse_leaf(parser)?);
     }
 
     while let Some(op) = op_stack.pop() {
@@ -312,35 +816,44 @@ fn parse_expr<'a>(parser: &mut Parser<'a>) -> Expr<'a> {
 
     if ternary {
         let expr_cond = expr_stack.pop().unwrap();
-        let expr_true = parse_expr(parser);
-        parser.take(LexToken::OpColon);
-        let expr_false = parse_expr(parser);
-        return Expr::If(Box::new(expr_cond),Box::new(expr_true),Some(Box::new(expr_false)));
+        let expr_true = parse_expr(parser)?;
+        parser.take(LexToken::OpColon)?;
+        let expr_false = parse_expr(parser)?;
+        return Ok(Expr::If(Box::new(expr_cond),Box::new(expr_true),Some(Box::new(expr_false))));
     }
 
-    expr_stack.pop().unwrap()
+    Ok(expr_stack.pop().unwrap())
 }
 
-fn parse_leaf<'a>(parser: &mut Parser<'a>) -> Expr<'a> {
-    let tok = parser.next();
+fn parse_leaf<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Expr<'a>> {
+    let tok = parser.next_token()?;
 
-    match tok {
+    Ok(match tok.kind {
         LexToken::Ident(id) => {
-            if parser.peek() == LexToken::OpParenOpen {
+            let (path,params,has_params) = parse_item_path(parser,id)?;
+
+            if has_params || parser.peek()? == LexToken::OpParenOpen {
                 let mut sub_args = Vec::new();
-                parser.take(LexToken::OpParenOpen);
+                parser.take(LexToken::OpParenOpen)?;
 
-                if parser.peek() == LexToken::OpParenClose {
-                    parser.take(LexToken::OpParenClose);
+                if parser.peek()? == LexToken::OpParenClose {
+                    parser.take(LexToken::OpParenClose)?;
                 } else {
                     loop {
-                        sub_args.push(parse_expr(parser));
-                        if parser.take_comma_or_close_paren() {
+                        sub_args.push(parse_expr(parser)?);
+                        if parser.take_comma_or_close_paren()? {
                             break;
                         }
                     }
                 }
-                Expr::SubModule(id.to_owned(),sub_args)
+                Expr::SubModule(path,params,sub_args)
+            } else if path.namespace.is_some() {
+                Expr::Path(path)
+            } else if parser.peek()? == LexToken::OpBracketOpen {
+                parser.take(LexToken::OpBracketOpen)?;
+                let index = parse_expr(parser)?;
+                parser.take(LexToken::OpBracketClose)?;
+                Expr::Index(id,Box::new(index))
             } else {
                 Expr::Ident(id)
             }
@@ -364,60 +877,184 @@ fn parse_leaf<'a>(parser: &mut Parser<'a>) -> Expr<'a> {
             Expr::If(Box::new(cond),Box::new(val_true),val_false)
         },*/
         LexToken::KeyMatch => {
-            parser.take(LexToken::OpParenOpen);
-            let in_expr = parse_expr(parser);
-            parser.take(LexToken::OpParenClose);
-
-            parser.take(LexToken::OpBraceOpen);
-            let mut match_list = Vec::new();
-            loop {
-                if parser.peek() == LexToken::OpBraceClose {
-                    parser.next();
-                    break;
-                }
-
-                let op = if parser.peek().is_compare_op() {
-                    match parser.next() {
-                        LexToken::OpCmpEq => BinOp::CmpEq,
-                        LexToken::OpCmpNeq => BinOp::CmpNeq,
-                        LexToken::OpCmpLt => BinOp::CmpLt,
-                        LexToken::OpCmpGt => BinOp::CmpGt,
-                        LexToken::OpCmpLeq => BinOp::CmpLeq,
-                        LexToken::OpCmpGeq => BinOp::CmpGeq,
-                        _ => panic!()
-                    }
-                } else {
-                    BinOp::CmpEq
-                };
-
-                let test_expr = parse_expr(parser);
-                parser.take(LexToken::OpMatchArrow);
-                let res_expr = parse_expr(parser);
-
-                match_list.push((op,test_expr,res_expr));
-                
-                let next_token = parser.next();
-                if next_token == LexToken::OpBraceClose {
-                    break;
-                } else if next_token != LexToken::OpComma {
-                    panic!("Expected ',' or '}}', found {:?}.",next_token);
-                }
-            }
+            parser.take(LexToken::OpParenOpen)?;
+            let in_expr = parse_expr(parser)?;
+            parser.take(LexToken::OpParenClose)?;
 
+            parser.take(LexToken::OpBraceOpen)?;
+            let match_list = parse_match_arms(parser)?;
             Expr::Match(Box::new(in_expr),match_list)
         },
         LexToken::Number(num) => Expr::Constant(num),
         LexToken::OpParenOpen => {
             // This *can* be done in the normal expression parser without recursion, but it's cleaner to do here.
-            let expr = parse_expr(parser);
-            parser.take(LexToken::OpParenClose);
+            let expr = parse_expr(parser)?;
+            parser.take(LexToken::OpParenClose)?;
             expr
         },
-        LexToken::OpAdd => Expr::UnOp(UnaryOp::Plus, Box::new(parse_leaf(parser))),
-        LexToken::OpSub => Expr::UnOp(UnaryOp::Negate, Box::new(parse_leaf(parser))),
-        LexToken::OpNotBitwise => Expr::UnOp(UnaryOp::NotBitwise, Box::new(parse_leaf(parser))),
-        LexToken::OpNotLogical => Expr::UnOp(UnaryOp::NotLogical, Box::new(parse_leaf(parser))),
+        LexToken::OpAdd => Expr::UnOp(UnaryOp::Plus, Box::new(parse_leaf(parser)?)),
+        LexToken::OpSub => Expr::UnOp(UnaryOp::Negate, Box::new(parse_leaf(parser)?)),
+        LexToken::OpNotBitwise => Expr::UnOp(UnaryOp::NotBitwise, Box::new(parse_leaf(parser)?)),
+        LexToken::OpNotLogical => Expr::UnOp(UnaryOp::NotLogical, Box::new(parse_leaf(parser)?)),
+
+        _ => return Err(parser.unexpected("expression",tok))
+    })
+}
+
+/// Parses the rest of a path that starts with the given identifier.
+/// Either `name`, `ns::name`, `name::<...>` or `ns::name::<...>`.
+/// Returns the path, any compile-time parameters, and whether parameters were given.
+fn parse_item_path<'a>(parser: &mut Parser<'a,'_>, id: &'a str) -> ParseResult<(ItemPath<'a>,Vec<Expr<'a>>,bool)> {
+    let mut path = ItemPath{namespace: None, name: id};
+    let mut params = Vec::new();
+    let mut has_params = false;
+
+    if parser.peek()? == LexToken::OpPath {
+        parser.take(LexToken::OpPath)?;
+        if parser.peek()? != LexToken::OpCmpLt {
+            path = ItemPath{namespace: Some(id), name: parser.take_ident()?};
+            if parser.peek()? == LexToken::OpPath {
+                parser.take(LexToken::OpPath)?;
+                params = parse_params(parser)?;
+                has_params = true;
+            }
+        } else {
+            params = parse_params(parser)?;
+            has_params = true;
+        }
+    }
+    Ok((path,params,has_params))
+}
+
+/// Parses compile-time parameters at a call site, like `<8, 2>`. Each parameter is a single leaf,
+/// so expressions using `>` must be wrapped in parens: `<(A > B)>`.
+fn parse_params<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Vec<Expr<'a>>> {
+    parser.take(LexToken::OpCmpLt)?;
+    let mut params = Vec::new();
+    loop {
+        params.push(parse_leaf(parser)?);
+        if parser.take_comma_or_close_angle()? {
+            break;
+        }
+    }
+    Ok(params)
+}
+
+/// Parses match arms up to and including the closing `}`.
+fn parse_match_arms<'a>(parser: &mut Parser<'a,'_>) -> ParseResult<Vec<MatchArm<'a>>> {
+    let mut match_list = Vec::new();
+    loop {
+        if parser.peek()? == LexToken::OpBraceClose {
+            parser.next()?;
+            break;
+        }
+
+        if parser.peek()? == LexToken::KeyFor {
+            parser.take(LexToken::KeyFor)?;
+            let (var,start,end) = parse_for_range(parser)?;
+            parser.take(LexToken::OpBraceOpen)?;
+            match_list.push(MatchArm::For(var,start,end,parse_match_arms(parser)?));
+
+            // The comma after a loop is optional.
+            if parser.peek()? == LexToken::OpComma {
+                parser.next()?;
+            }
+            continue;
+        }
+
+        let op = if parser.peek()?.is_compare_op() {
+            match parser.next()? {
+                LexToken::OpCmpEq => BinOp::CmpEq,
+                LexToken::OpCmpNeq => BinOp::CmpNeq,
+                LexToken::OpCmpLt => BinOp::CmpLt,
+                LexToken::OpCmpGt => BinOp::CmpGt,
+                LexToken::OpCmpLeq => BinOp::CmpLeq,
+                LexToken::OpCmpGeq => BinOp::CmpGeq,
+                _ => unreachable!()
+            }
+        } else {
+            BinOp::CmpEq
+        };
+
+        let test_expr = parse_expr(parser)?;
+        parser.take(LexToken::OpMatchArrow)?;
+        let res_expr = parse_expr(parser)?;
+
+        match_list.push(MatchArm::Case(op,test_expr,res_expr));
+        
+        let next_token = parser.next_token()?;
+        if next_token.kind == LexToken::OpBraceClose {
+            break;
+        } else if next_token.kind != LexToken::OpComma {
+            return Err(parser.unexpected("`,` or `}`",next_token));
+        }
+    }
+    Ok(match_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::line_col;
+
+    /// The line, column and message of each error in a source file.
+    fn errors(source: &str) -> Vec<(usize,usize,String)> {
+        let symbols = SymbolTable::default();
+        let errors = parse(source,&symbols).err().unwrap_or_default();
+        errors.into_iter().map(|err| {
+            let (line,col) = line_col(source,err.span.start);
+            (line,col,err.message)
+        }).collect()
+    }
+
+    #[test]
+    fn recovers_after_statement_errors() {
+        let source = "\
+mod a(x) {
+    let y = x + ;
+    let z = 3;
+    output(y z);
+}
+
+mod b(x) {
+    let w = (x * 2;
+    let v = w + 1;
+    output(v);
+}
+";
+        assert_eq!(errors(source),[
+            (2,17,"Expected expression, found `;`.".to_owned()),
+            (4,14,"Expected operator, found identifier `z`.".to_owned()),
+            (8,19,"Expected `)`, found `;`.".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn recovers_after_item_errors() {
+        let source = "\
+mod a(x y) {
+    output(x);
+}
+
+const K = ;
+
+mod b(x) {
+    output(x);
+}
+
+mod c(x) {
+    output(x +);
+}
+";
+        assert_eq!(errors(source),[
+            (1,9,"Expected `,` or `)`, found identifier `y`.".to_owned()),
+            (5,11,"Expected expression, found `;`.".to_owned()),
+            (12,15,"Expected expression, found `)`.".to_owned())
+        ]);
+    }
 
-        _ => panic!("Expected expression, found {:?}.",tok)
+    #[test]
+    fn valid_source_has_no_errors() {
+        assert_eq!(errors("mod a(x) {\n    let y = x + 1;\n    output(y);\n}\n"),[]);
     }
 }
@@ -0,0 +1,16 @@
+use std::rc::Rc;
+
+use crate::CompileSettings;
+use crate::symbols::SymbolTable;
+
+/// State shared by every stage of a single compilation.
+pub struct Session {
+    pub settings: Rc<CompileSettings>,
+    pub symbols: SymbolTable
+}
+
+impl Session {
+    pub fn new(settings: CompileSettings, symbols: SymbolTable) -> Self {
+        Session{settings: Rc::new(settings),symbols}
+    }
+}
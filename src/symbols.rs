@@ -1,12 +1,28 @@
 use serde::{Serialize, Deserialize};
 
-use once_cell::sync::OnceCell;
 use std::collections::HashMap;
 use crate::blueprint::Signal;
 
-struct SymbolInfo {
+/// Maps the `$NAME` identifiers used in source code to in-game signals.
+/// Signals are referred to by their index in the table everywhere after parsing.
+#[derive(Debug)]
+pub struct SymbolTable {
     signals: Vec<Signal>,
-    ident_map: HashMap<String, u32>
+    /// The id of each symbol in upper case, for printing.
+    names: Vec<String>,
+    ident_map: HashMap<String, u32>,
+    /// If several ids refer to the same signal, the first one defined is used.
+    signal_map: HashMap<Signal, u32>,
+    /// The signal categories the allocator may pick from when a value has no declared type.
+    auto_categories: Vec<String>
+}
+
+impl Default for SymbolTable {
+    /// Item signals are left out of automatic allocation by default, since they can collide with
+    /// logistic networks that share the same wires.
+    fn default() -> Self {
+        SymbolTable{signals: Vec::new(), names: Vec::new(), ident_map: HashMap::new(), signal_map: HashMap::new(), auto_categories: vec!("virtual".to_owned())}
+    }
 }
 
 #[derive(Debug,Serialize,Deserialize)]
@@ -15,30 +31,220 @@ struct ParseSymbol{
     signal: Signal
 }
 
-static SYMBOL_INFO: OnceCell<SymbolInfo> = OnceCell::new();
+/// The signal categories Factorio accepts in a blueprint.
+const SIGNAL_TYPES: [&str;3] = ["virtual","item","fluid"];
+
+impl SymbolTable {
+    /// Adds the definitions from a symbol file. Ids that are already defined are overridden,
+    /// so a project can remap the embedded symbols as well as add new ones.
+    /// On failure, returns every problem found and leaves the table unchanged.
+    pub fn add_json(&mut self, json: &str) -> Result<(),Vec<String>> {
+        let symbols: Vec<ParseSymbol> = serde_json::from_str(json).map_err(|err| vec!(format!("Bad symbol definitions: {}",err)))?;
+
+        let mut errors = Vec::new();
+        let mut seen: HashMap<String,&str> = HashMap::new();
+        for symbol in &symbols {
+            let key = symbol.id.to_uppercase();
+            if let Some(other) = seen.insert(key,&symbol.id) {
+                errors.push(format!("Symbol id '{}' is defined more than once (also as '{}').",symbol.id,other));
+            }
+            let is_ident = !symbol.id.is_empty() && symbol.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_ident {
+                errors.push(format!("Symbol id '{}' is not valid, ids may only contain letters, digits and underscores.",symbol.id));
+            }
+            if !SIGNAL_TYPES.contains(&symbol.signal.cat.as_str()) {
+                errors.push(format!("Symbol '{}' has invalid signal type '{}', expected one of: {}.",
+                    symbol.id,symbol.signal.cat,SIGNAL_TYPES.join(", ")));
+            }
+            if symbol.signal.name.is_empty() {
+                errors.push(format!("Symbol '{}' has an empty signal name.",symbol.id));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
 
-pub fn load_symbols(json: &str) {
-    let symbols: Vec<ParseSymbol> = serde_json::from_str(&json).expect("bad json");
-    let mut info = SymbolInfo{signals:Vec::new(),ident_map:HashMap::new()};
-    for symbol in symbols {
-        let index = info.signals.len() as u32;
-        info.signals.push(symbol.signal);
-        info.ident_map.insert(symbol.id.to_uppercase(),index);
+        for symbol in symbols {
+            let key = symbol.id.to_uppercase();
+            if let Some(&index) = self.ident_map.get(&key) {
+                let old_signal = std::mem::replace(&mut self.signals[index as usize],symbol.signal.clone());
+                if self.signal_map.get(&old_signal) == Some(&index) {
+                    self.remap_signal(old_signal);
+                }
+                // The override is what the project asked for, so it takes the signal over from any other id.
+                self.signal_map.insert(symbol.signal,index);
+            } else {
+                let index = self.signals.len() as u32;
+                self.signals.push(symbol.signal.clone());
+                self.names.push(key.clone());
+                self.ident_map.insert(key,index);
+                self.signal_map.entry(symbol.signal).or_insert(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Points a signal at the first id that still uses it, or forgets it if none do.
+    fn remap_signal(&mut self, signal: Signal) {
+        match self.signals.iter().position(|other| *other == signal) {
+            Some(index) => self.signal_map.insert(signal,index as u32),
+            None => self.signal_map.remove(&signal)
+        };
+    }
+
+    /// Restricts automatic allocation to the given signal categories.
+    pub fn set_auto_categories(&mut self, categories: &[&str]) -> Result<(),String> {
+        if categories.is_empty() {
+            return Err("At least one signal category must be allowed for automatic allocation.".to_owned());
+        }
+        for cat in categories {
+            if !SIGNAL_TYPES.contains(cat) {
+                return Err(format!("Invalid signal category '{}', expected one of: {}.",cat,SIGNAL_TYPES.join(", ")));
+            }
+        }
+        self.auto_categories = categories.iter().map(|x| (*x).to_owned()).collect();
+        Ok(())
+    }
+
+    /// Every symbol the allocator may pick, in table order.
+    pub fn auto_symbols(&self) -> Vec<u32> {
+        self.signals.iter().enumerate()
+            .filter(|(_,signal)| self.auto_categories.contains(&signal.cat))
+            .map(|(index,_)| index as u32)
+            .collect()
+    }
+
+    pub fn signal_from_symbol_index(&self, index: u32) -> Signal {
+        self.signals[index as usize].clone()
+    }
+
+    pub fn symbol_index_from_identifier(&self, ident: &str) -> Option<u32> {
+        self.ident_map.get(&ident.to_uppercase()).copied()
+    }
+
+    pub fn symbol_index_from_signal(&self, signal: &Signal) -> Option<u32> {
+        self.signal_map.get(signal).copied()
+    }
+
+    /// A signal as it would be written in source code, or as `type:name` if no symbol refers to it.
+    pub fn signal_name(&self, signal: &Signal) -> String {
+        match self.symbol_index_from_signal(signal) {
+            Some(index) => self.symbol_name(index),
+            None => format!("{}:{}",signal.cat,signal.name)
+        }
     }
-    SYMBOL_INFO.set(info).ok();
-}
 
-// TODO bidirectional mapping
-pub fn signal_from_symbol_index(index: u32) -> Signal {
-    let info = SYMBOL_INFO.get().expect("symbol info not loaded");
-    info.signals[index as usize].clone()
+    /// The symbol as it would be written in source code, like `$A`.
+    pub fn symbol_name(&self, index: u32) -> String {
+        match self.names.get(index as usize) {
+            Some(name) => format!("${}",name),
+            None => format!("<bad symbol {}>",index)
+        }
+    }
 }
 
-pub fn symbol_index_from_identifier(ident: &str) -> u32 {
-    let info = SYMBOL_INFO.get().expect("symbol info not loaded");
-    if let Some(n) = info.ident_map.get(&ident.to_uppercase()) {
-        *n
-    } else {
-        panic!("Signal name '{}' does not exist.",ident);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(cat: &str, name: &str) -> Signal {
+        Signal{cat: cat.to_owned(), name: name.to_owned()}
+    }
+
+    #[test]
+    fn add_json() {
+        let mut table = SymbolTable::default();
+        table.add_json(r#"[
+            {"id": "A", "signal": {"type": "virtual", "name": "signal-A"}},
+            {"id": "plate", "signal": {"type": "item", "name": "iron-plate"}}
+        ]"#).unwrap();
+        let plate = table.symbol_index_from_identifier("Plate").unwrap();
+        assert_eq!(table.symbol_name(plate),"$PLATE");
+        assert_eq!(table.signal_from_symbol_index(plate),signal("item","iron-plate"));
+        assert_eq!(table.symbol_index_from_identifier("B"),None);
+    }
+
+    #[test]
+    fn add_json_duplicates() {
+        let mut table = SymbolTable::default();
+        let errors = table.add_json(r#"[
+            {"id": "A", "signal": {"type": "virtual", "name": "signal-A"}},
+            {"id": "a", "signal": {"type": "virtual", "name": "signal-B"}}
+        ]"#).unwrap_err();
+        assert_eq!(errors,["Symbol id 'a' is defined more than once (also as 'A')."]);
+        // Nothing is added when the file has errors.
+        assert_eq!(table.symbol_index_from_identifier("A"),None);
+    }
+
+    #[test]
+    fn add_json_bad_definitions() {
+        let mut table = SymbolTable::default();
+        let errors = table.add_json(r#"[
+            {"id": "A", "signal": {"type": "virtual", "name": "signal-A"}},
+            {"id": "B", "signal": {"type": "tile", "name": "stone-path"}},
+            {"id": "C-1", "signal": {"type": "virtual", "name": "signal-C"}},
+            {"id": "D", "signal": {"type": "item", "name": ""}}
+        ]"#).unwrap_err();
+        assert_eq!(errors.len(),3);
+        assert!(errors[0].contains("invalid signal type 'tile'"));
+        assert!(errors[1].contains("'C-1' is not valid"));
+        assert!(errors[2].contains("empty signal name"));
+        assert_eq!(table.symbol_index_from_identifier("A"),None);
+
+        let errors = table.add_json(r#"[{"id": "A"}]"#).unwrap_err();
+        assert!(errors[0].starts_with("Bad symbol definitions:"));
+    }
+
+    #[test]
+    fn override_moves_signal_lookup() {
+        let mut table = SymbolTable::default();
+        table.add_json(r#"[
+            {"id": "A", "signal": {"type": "virtual", "name": "signal-A"}},
+            {"id": "B", "signal": {"type": "virtual", "name": "signal-B"}}
+        ]"#).unwrap();
+        table.add_json(r#"[{"id": "a", "signal": {"type": "item", "name": "iron-plate"}}]"#).unwrap();
+
+        let a = table.symbol_index_from_identifier("A").unwrap();
+        assert_eq!(table.signal_from_symbol_index(a),signal("item","iron-plate"));
+        assert_eq!(table.symbol_index_from_signal(&signal("item","iron-plate")),Some(a));
+        assert_eq!(table.symbol_index_from_signal(&signal("virtual","signal-A")),None);
+
+        // Taking over a signal that another id already uses.
+        table.add_json(r#"[{"id": "A", "signal": {"type": "virtual", "name": "signal-B"}}]"#).unwrap();
+        assert_eq!(table.symbol_index_from_signal(&signal("virtual","signal-B")),Some(a));
+        assert_eq!(table.symbol_index_from_signal(&signal("item","iron-plate")),None);
+    }
+
+    #[test]
+    fn override_keeps_shared_signal() {
+        let mut table = SymbolTable::default();
+        table.add_json(r#"[
+            {"id": "A", "signal": {"type": "virtual", "name": "signal-A"}},
+            {"id": "ALIAS", "signal": {"type": "virtual", "name": "signal-A"}}
+        ]"#).unwrap();
+        let a = table.symbol_index_from_identifier("A").unwrap();
+        let alias = table.symbol_index_from_identifier("ALIAS").unwrap();
+        assert_eq!(table.symbol_index_from_signal(&signal("virtual","signal-A")),Some(a));
+
+        // The signal is still used by the alias, so it points there now.
+        table.add_json(r#"[{"id": "A", "signal": {"type": "virtual", "name": "signal-Z"}}]"#).unwrap();
+        assert_eq!(table.symbol_index_from_signal(&signal("virtual","signal-A")),Some(alias));
+        assert_eq!(table.signal_name(&signal("virtual","signal-A")),"$ALIAS");
+        assert_eq!(table.signal_name(&signal("virtual","signal-dot")),"virtual:signal-dot");
+    }
+
+    #[test]
+    fn tables_are_independent() {
+        let mut first = SymbolTable::default();
+        let mut second = SymbolTable::default();
+        first.add_json(r#"[{"id": "A", "signal": {"type": "virtual", "name": "signal-A"}}]"#).unwrap();
+        second.add_json(r#"[
+            {"id": "B", "signal": {"type": "virtual", "name": "signal-B"}},
+            {"id": "A", "signal": {"type": "item", "name": "iron-plate"}}
+        ]"#).unwrap();
+
+        assert_eq!(first.signal_from_symbol_index(first.symbol_index_from_identifier("A").unwrap()),signal("virtual","signal-A"));
+        assert_eq!(second.signal_from_symbol_index(second.symbol_index_from_identifier("A").unwrap()),signal("item","iron-plate"));
+        assert_eq!(first.symbol_index_from_identifier("B"),None);
     }
 }